@@ -7,11 +7,16 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also records the short git hash of the source in `GIT_HASH` so the
+//! firmware can report exactly what it was built from, and passes on the USB
+//! identity, see `usb_identity`.
 
 use std::env;
 use std::fs::File;
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::Command;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -28,4 +33,50 @@ fn main() {
     // here, we ensure the build script is only re-run when
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
+
+    // Fall back to "unknown" when building outside a git checkout.
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_HASH={}", hash);
+
+    usb_identity();
+
+    // Rebuild when the checked out commit moves.
+    if let Ok(head) = std::fs::read_to_string(".git/HEAD") {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = head.strip_prefix("ref: ") {
+            println!("cargo:rerun-if-changed=.git/{}", reference.trim());
+        }
+    }
+}
+
+/// USB vendor and product ids, in hex, and the manufacturer and product
+/// strings, taken from `USB_VID`, `USB_PID`, `USB_MANUFACTURER` and
+/// `USB_PRODUCT` at build time so a product can carry its own.
+fn usb_identity() {
+    for (name, default) in [
+        ("USB_VID", "cafe"),
+        ("USB_PID", "27dd"),
+        ("USB_MANUFACTURER", "Field Home I/O"),
+        ("USB_PRODUCT", "Pico I/O Expander"),
+    ] {
+        println!("cargo:rerun-if-env-changed={}", name);
+        let value = env::var(name).unwrap_or_else(|_| default.to_string());
+        let value = if name.ends_with("ID") {
+            let hex = value.trim_start_matches("0x");
+            match u16::from_str_radix(hex, 16) {
+                Ok(id) => format!("{:04x}", id),
+                Err(_) => panic!("{} must be a 16 bit hex id, not {:?}", name, value),
+            }
+        } else {
+            value
+        };
+        println!("cargo:rustc-env={}={}", name, value);
+    }
 }
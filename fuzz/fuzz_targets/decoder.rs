@@ -0,0 +1,37 @@
+//! Feeds arbitrary serial input to the decoders one byte at a time, any panic
+//! is a crash and anything that fails to return is reported as a timeout.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[allow(dead_code, unused_macros, unused_imports)]
+#[path = "../../src/text.rs"]
+mod text;
+
+#[allow(dead_code)]
+#[path = "../../src/decoder.rs"]
+mod decoder;
+
+use decoder::{BinaryDecoder, Decoder, Instant};
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks the decoder settings, so each sees every input.
+    let Some((&settings, input)) = data.split_first() else {
+        return;
+    };
+    let mut decoder: Decoder = if settings & 1 == 0 {
+        Decoder::new()
+    } else {
+        Decoder::new_with_terminator(b'\r')
+    };
+    decoder.echo = settings & 2 != 0;
+    let mut binary = BinaryDecoder::new();
+    let mut now = 0u64;
+    for c in input {
+        decoder.run(c);
+        binary.run(c);
+        // Let some bytes arrive late enough to time out the partial command.
+        now += if *c == 0xff { 3_000_000 } else { 1_000 };
+        decoder.tick(Instant::from_ticks(now));
+    }
+});
@@ -0,0 +1,79 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::{
+    adc::{Adc, TempSense},
+    gpio::{
+        bank0::{Gpio26, Gpio27, Gpio28, Gpio29},
+        FloatingInput, Pin,
+    },
+};
+use embedded_hal::adc::OneShot;
+
+/// ADC reference voltage in microvolts.
+const VREF_UV: i64 = 3_300_000;
+/// Full scale of the 12-bit ADC.
+const ADC_FULL_SCALE: i64 = 4096;
+
+/// VSYS reaches ADC3 through a divide by three.
+const VSYS_DIVIDER: i64 = 3;
+
+/// External inputs ADC0 - ADC2.
+pub type Channels = (
+    Pin<Gpio26, FloatingInput>,
+    Pin<Gpio27, FloatingInput>,
+    Pin<Gpio28, FloatingInput>,
+);
+
+/// VSYS/3 on GPIO29. The Pico divider is always connected. A Pico W instead
+/// needs GPIO25 high to connect it, and shares both pins with the wireless
+/// chip, but on the Pico GPIO25 is the onboard led so it is left alone.
+pub type Vsys = Pin<Gpio29, FloatingInput>;
+
+pub struct Analog {
+    adc: Adc,
+    temp_sense: TempSense,
+    channels: Channels,
+    vsys: Vsys,
+}
+
+impl Analog {
+    pub fn new(mut adc: Adc, channels: Channels, vsys: Vsys) -> Analog {
+        let temp_sense = adc.enable_temp_sensor();
+        Analog {
+            adc,
+            temp_sense,
+            channels,
+            vsys,
+        }
+    }
+
+    /// Voltage on an external channel in millivolts, None if there is no such
+    /// channel.
+    pub fn millivolts(&mut self, channel: u8) -> Option<u16> {
+        // Conversion blocks until complete so never returns WouldBlock.
+        let raw: u16 = match channel {
+            0 => self.adc.read(&mut self.channels.0).unwrap(),
+            1 => self.adc.read(&mut self.channels.1).unwrap(),
+            2 => self.adc.read(&mut self.channels.2).unwrap(),
+            _ => return None,
+        };
+        Some((raw as i64 * VREF_UV / ADC_FULL_SCALE / 1000) as u16)
+    }
+
+    /// Supply voltage on VSYS in millivolts.
+    pub fn vsys(&mut self) -> u16 {
+        // Conversion blocks until complete so never returns WouldBlock.
+        let raw: u16 = self.adc.read(&mut self.vsys).unwrap();
+        (raw as i64 * VREF_UV * VSYS_DIVIDER / ADC_FULL_SCALE / 1000) as u16
+    }
+
+    /// On-die temperature in millidegrees Celsius.
+    pub fn temperature(&mut self) -> i32 {
+        // Conversion blocks until complete so never returns WouldBlock.
+        let raw: u16 = self.adc.read(&mut self.temp_sense).unwrap();
+        let uv = raw as i64 * VREF_UV / ADC_FULL_SCALE;
+        // From the datasheet: T = 27 - (V - 0.706) / 0.001721
+        (27_000 - (uv - 706_000) * 1000 / 1721) as i32
+    }
+}
@@ -0,0 +1,51 @@
+/// Line that leaves bridge mode, as it would enter it.
+const EXIT_LINE: &[u8] = b"!bridge";
+
+/// Consecutive escapes that leave bridge mode, for hosts that cannot send a
+/// whole line.
+const EXIT_ESCAPES: u8 = 3;
+
+/// Watches host bytes passed through to the UART for a way out of bridge mode.
+pub struct Bridge {
+    escapes: u8,
+    // Bytes of the exit line matched since the start of the line, None once
+    // the line can no longer match.
+    matched: Option<usize>,
+}
+
+impl Bridge {
+    pub fn new() -> Bridge {
+        Bridge {
+            escapes: 0,
+            matched: Some(0),
+        }
+    }
+
+    /// Feed a byte from the host, true once it completes an exit sequence.
+    pub fn exit(&mut self, c: u8) -> bool {
+        if c == 27 {
+            self.escapes += 1;
+        } else {
+            self.escapes = 0;
+        }
+        let exit = match (c, self.matched) {
+            (b'\r' | b'\n', matched) => {
+                self.matched = Some(0);
+                matched == Some(EXIT_LINE.len())
+            }
+            (_, Some(n)) if EXIT_LINE.get(n) == Some(&c) => {
+                self.matched = Some(n + 1);
+                false
+            }
+            _ => {
+                self.matched = None;
+                false
+            }
+        };
+        if exit || self.escapes == EXIT_ESCAPES {
+            *self = Bridge::new();
+            return true;
+        }
+        false
+    }
+}
@@ -0,0 +1,127 @@
+use crate::flash::{self, PAGE_SIZE};
+use crate::led::{Mode, LED_COUNT};
+use crate::valve::VALVE_COUNT;
+
+/// Marks a page written by `save`, anything else is ignored.
+const MAGIC: [u8; 4] = *b"PXC4";
+
+/// Bytes covered by the checksum, which follows them.
+const LENGTH: usize = MAGIC.len() + LED_COUNT * 5 + 2 + 4 + VALVE_COUNT * 4 + 2 + 1;
+
+/// Settings restored at boot once saved with `!save`.
+pub struct Config {
+    /// Mode and its rate, period or brightness, for each led target.
+    pub leds: [(Mode, u32); LED_COUNT],
+    /// Open valves, bit n for valve n.
+    pub valves: u16,
+    pub baud: u32,
+    /// Times each valve has opened or closed, see `!valvestats`.
+    pub actuations: [u32; VALVE_COUNT],
+    /// Active-low valves, bit n for valve n.
+    pub inverted: u16,
+    /// No banner as a terminal connects, see `!quiet`.
+    pub quiet: bool,
+}
+
+impl Config {
+    /// The saved configuration, None if nothing valid has been saved.
+    pub fn load() -> Option<Config> {
+        decode(&flash::read_config())
+    }
+
+    pub fn save(&self) {
+        flash::write_config(&encode(self));
+    }
+
+    /// Forget the saved configuration, the defaults apply from next boot.
+    pub fn erase() {
+        flash::erase_config();
+    }
+}
+
+// Magic, each led as a mode byte and a value, valves, baud, the valve
+// actuation counts, the inverted valves, quiet, then the checksum of everything
+// before it. The rest of the page is left erased.
+fn encode(config: &Config) -> [u8; PAGE_SIZE] {
+    let mut page = [0xffu8; PAGE_SIZE];
+    let mut at = 0;
+    let mut put = |bytes: &[u8]| {
+        page[at..at + bytes.len()].copy_from_slice(bytes);
+        at += bytes.len();
+    };
+    put(&MAGIC);
+    for (mode, value) in config.leds {
+        put(&[mode_code(mode)]);
+        put(&value.to_le_bytes());
+    }
+    put(&config.valves.to_le_bytes());
+    put(&config.baud.to_le_bytes());
+    for count in config.actuations {
+        put(&count.to_le_bytes());
+    }
+    put(&config.inverted.to_le_bytes());
+    put(&[config.quiet as u8]);
+    let sum = checksum(&page[..LENGTH]);
+    page[LENGTH..LENGTH + 4].copy_from_slice(&sum.to_le_bytes());
+    page
+}
+
+fn decode(page: &[u8; PAGE_SIZE]) -> Option<Config> {
+    let sum = u32::from_le_bytes(page[LENGTH..LENGTH + 4].try_into().unwrap());
+    if page[..MAGIC.len()] != MAGIC || checksum(&page[..LENGTH]) != sum {
+        return None;
+    }
+    let mut at = MAGIC.len();
+    let mut leds = [(Mode::Blink, 0); LED_COUNT];
+    for led in leds.iter_mut() {
+        let value = u32::from_le_bytes(page[at + 1..at + 5].try_into().unwrap());
+        *led = (code_mode(page[at])?, value);
+        at += 5;
+    }
+    let valves = u16::from_le_bytes([page[at], page[at + 1]]);
+    let baud = u32::from_le_bytes(page[at + 2..at + 6].try_into().unwrap());
+    at += 6;
+    let mut actuations = [0; VALVE_COUNT];
+    for count in actuations.iter_mut() {
+        *count = u32::from_le_bytes(page[at..at + 4].try_into().unwrap());
+        at += 4;
+    }
+    let inverted = u16::from_le_bytes([page[at], page[at + 1]]);
+    let quiet = page[at + 2] == 1;
+    Some(Config {
+        leds,
+        valves,
+        baud,
+        actuations,
+        inverted,
+        quiet,
+    })
+}
+
+// Only the steady modes are saved, a Morse message or pulse is transient.
+fn mode_code(mode: Mode) -> u8 {
+    match mode {
+        Mode::Breathe => 1,
+        Mode::Solid => 2,
+        _ => 0,
+    }
+}
+
+fn code_mode(code: u8) -> Option<Mode> {
+    match code {
+        0 => Some(Mode::Blink),
+        1 => Some(Mode::Breathe),
+        2 => Some(Mode::Solid),
+        _ => None,
+    }
+}
+
+// Fletcher-32 style, so an erased page of 0xff doesn't pass.
+fn checksum(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
@@ -1,11 +1,110 @@
 // Use alias bsp so we can switch boards at a single location
 use rp_pico as bsp;
 
-use bsp::hal::uart::{
-    DataBits, Disabled, Enabled, StopBits, UartConfig, UartDevice, UartPeripheral, ValidUartPinout,
+use bsp::hal::{
+    pac::{dma::CH, DMA, RESETS},
+    uart::{
+        DataBits, Disabled, Enabled, Parity, StopBits, UartConfig, UartDevice, UartPeripheral,
+        ValidUartPinout,
+    },
+    Timer,
 };
+use core::fmt;
+use core::ptr::{read_volatile, write_volatile};
 use fugit::{HertzU32, RateExtU32};
 
+/// Baud rates the console can be switched to.
+const BAUD_RATES: [u32; 5] = [9600, 19_200, 38_400, 57_600, 115_200];
+const DEFAULT_BAUD: u32 = 115_200;
+
+/// Character framing, written as data bits, parity and stop bits like `8N1`.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct Format {
+    data_bits: u8,
+    /// `N`one, `E`ven or `O`dd.
+    parity: u8,
+    stop_bits: u8,
+}
+
+impl Format {
+    pub const DEFAULT: Format = Format {
+        data_bits: 8,
+        parity: b'N',
+        stop_bits: 1,
+    };
+
+    /// Parse `8N1` style framing, None for anything the UART can't do.
+    pub fn parse(text: &str) -> Option<Format> {
+        match text.as_bytes() {
+            [data_bits @ b'5'..=b'8', parity, stop_bits @ (b'1' | b'2')] => {
+                let parity = parity.to_ascii_uppercase();
+                if !matches!(parity, b'N' | b'E' | b'O') {
+                    return None;
+                }
+                Some(Format {
+                    data_bits: data_bits - b'0',
+                    parity,
+                    stop_bits: stop_bits - b'0',
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            self.data_bits, self.parity as char, self.stop_bits
+        )
+    }
+}
+
+/// Receive status register bits, UARTRSR.
+const FRAMING_ERROR: u32 = 1 << 0;
+const PARITY_ERROR: u32 = 1 << 1;
+const BREAK_ERROR: u32 = 1 << 2;
+const OVERRUN_ERROR: u32 = 1 << 3;
+
+/// Receive errors seen since they were last taken. Each counts the reads that
+/// found the error flagged, so a burst of bad characters may count once.
+#[derive(Default, Clone, Copy)]
+pub struct Errors {
+    pub overrun: u32,
+    pub framing: u32,
+    pub parity: u32,
+    pub breaks: u32,
+}
+
+/// Control register bits, UARTCR, letting CTS hold off transmission and RTS
+/// follow the receive FIFO.
+const CTS_ENABLE: u32 = 1 << 15;
+const RTS_ENABLE: u32 = 1 << 14;
+
+/// Receive ring size, a power of two as the DMA wraps on the address bits.
+const RX_RING_SIZE: usize = 256;
+const RX_RING_BITS: u8 = 8;
+
+/// Where a `DmaRx` keeps what it has received.
+// The DMA ring wraps on address bits, so the buffer must be aligned to its size.
+#[repr(C, align(256))]
+pub struct RxRing([u8; RX_RING_SIZE]);
+
+impl RxRing {
+    pub fn new() -> RxRing {
+        RxRing([0; RX_RING_SIZE])
+    }
+}
+
+/// Take the DMA block out of reset, once before any `DmaRx` is made. Each
+/// `DmaRx` then drives its own channel.
+pub fn enable_dma(_dma: DMA, resets: &mut RESETS) {
+    resets.reset.modify(|_, w| w.dma().clear_bit());
+    while resets.reset_done.read().dma().bit_is_clear() {}
+}
+
 struct Buffer {
     rpos: usize,
     wpos: usize,
@@ -29,6 +128,13 @@ impl Buffer {
         }
     }
 
+    fn peek(&self) -> Option<u8> {
+        if !self.empty() {
+            return Some(self.buffer[self.rpos]);
+        }
+        None
+    }
+
     fn get(&mut self) -> Option<u8> {
         let npos = (self.rpos + 1) & 63;
         if self.rpos != self.wpos {
@@ -42,43 +148,157 @@ impl Buffer {
     fn empty(&self) -> bool {
         self.rpos == self.wpos
     }
+
+    fn full(&self) -> bool {
+        (self.wpos + 1) & 63 == self.rpos
+    }
+
+    fn clear(&mut self) {
+        self.rpos = self.wpos;
+    }
+}
+
+/// Copies received bytes from the UART into a ring as they arrive, so input
+/// survives while the command task is busy.
+pub struct DmaRx {
+    channel: usize,
+    ring: &'static mut RxRing,
+    rpos: usize,
+    dreq: u8,
+}
+
+impl DmaRx {
+    /// `channel` must be used by nothing else, `source` is the address of the
+    /// UART data register and `dreq` its receive request line.
+    pub fn new(channel: usize, ring: &'static mut RxRing, source: u32, dreq: u8) -> DmaRx {
+        let rx = DmaRx {
+            channel,
+            ring,
+            rpos: 0,
+            dreq,
+        };
+        let registers = rx.registers();
+        registers.ch_read_addr.write(|w| unsafe { w.bits(source) });
+        registers
+            .ch_write_addr
+            .write(|w| unsafe { w.bits(rx.ring.0.as_ptr() as u32) });
+        rx.start();
+        rx
+    }
+
+    fn registers(&self) -> &CH {
+        // Only this DmaRx touches its channel.
+        unsafe { &(*DMA::ptr()).ch[self.channel] }
+    }
+
+    // Run for as many bytes as possible, carrying on from the current position.
+    fn start(&self) {
+        let registers = self.registers();
+        registers
+            .ch_trans_count
+            .write(|w| unsafe { w.bits(u32::MAX) });
+        registers.ch_ctrl_trig.write(|w| unsafe {
+            w.treq_sel().bits(self.dreq);
+            // Chaining to itself disables chaining.
+            w.chain_to().bits(self.channel as u8);
+            w.ring_sel().set_bit();
+            w.ring_size().bits(RX_RING_BITS);
+            w.incr_write().set_bit();
+            w.incr_read().clear_bit();
+            w.data_size().size_byte();
+            w.irq_quiet().set_bit();
+            w.en().set_bit()
+        });
+    }
+
+    /// Copy out whatever has arrived since the last read, returns the count.
+    /// Input is lost if more than the ring holds arrives between reads.
+    pub fn read(&mut self, buffer: &mut [u8]) -> usize {
+        let registers = self.registers();
+        let wpos = registers.ch_write_addr.read().bits() as usize & (RX_RING_SIZE - 1);
+        if registers.ch_ctrl_trig.read().busy().bit_is_clear() {
+            self.start();
+        }
+        let mut count = 0;
+        while self.rpos != wpos && count < buffer.len() {
+            buffer[count] = unsafe { read_volatile(&self.ring.0[self.rpos]) };
+            self.rpos = (self.rpos + 1) & (RX_RING_SIZE - 1);
+            count += 1;
+        }
+        count
+    }
 }
 
 pub struct Console<D: UartDevice, P: ValidUartPinout<D>> {
-    uart: UartPeripheral<Enabled, D, P>,
+    // Only None while the baud rate is being changed.
+    uart: Option<UartPeripheral<Enabled, D, P>>,
+    frequency: HertzU32,
+    baud: u32,
+    format: Format,
     buffer: Buffer,
+    dma_rx: DmaRx,
+    // Address of the receive status register, cleared by any write.
+    status: u32,
+    errors: Errors,
+    // Address of the control register, where flow control is switched.
+    control: u32,
+    flow: bool,
+}
+
+fn config(baud: u32, format: Format) -> UartConfig {
+    let data_bits = match format.data_bits {
+        5 => DataBits::Five,
+        6 => DataBits::Six,
+        7 => DataBits::Seven,
+        _ => DataBits::Eight,
+    };
+    let parity = match format.parity {
+        b'E' => Some(Parity::Even),
+        b'O' => Some(Parity::Odd),
+        _ => None,
+    };
+    let stop_bits = match format.stop_bits {
+        2 => StopBits::Two,
+        _ => StopBits::One,
+    };
+    UartConfig::new(baud.Hz(), data_bits, parity, stop_bits)
 }
 
 impl<D: UartDevice, P: ValidUartPinout<D>> Console<D, P> {
-    pub fn new(uart: UartPeripheral<Disabled, D, P>, frequency: HertzU32) -> Console<D, P> {
+    /// `status` and `control` are the addresses of the UART receive status and
+    /// control registers.
+    pub fn new(
+        uart: UartPeripheral<Disabled, D, P>,
+        frequency: HertzU32,
+        dma_rx: DmaRx,
+        status: u32,
+        control: u32,
+    ) -> Console<D, P> {
+        // Enabling also turns on the DMA requests.
         let uart = uart
-            .enable(
-                UartConfig::new(115_200.Hz(), DataBits::Eight, None, StopBits::One),
-                frequency,
-            )
+            .enable(config(DEFAULT_BAUD, Format::DEFAULT), frequency)
             .unwrap();
         Console {
-            uart,
+            uart: Some(uart),
+            frequency,
+            baud: DEFAULT_BAUD,
+            format: Format::DEFAULT,
             buffer: Buffer::new(),
+            dma_rx,
+            status,
+            errors: Errors::default(),
+            control,
+            flow: false,
         }
     }
 
     pub fn read(&mut self, buffer: &mut [u8]) -> Option<usize> {
-        while self.uart.uart_is_writable() && !self.buffer.empty() {
-            if let Some(out) = self.buffer.get() {
-                let out = [out];
-                if let Ok(_count) = self.uart.write_raw(&out) {}
-            }
-        }
-        if self.uart.uart_is_readable() {
-            match self.uart.read_raw(buffer) {
-                Ok(0) => {}
-                Err(_) => {}
-                // Echo the input for now.
-                Ok(count) => return Some(count),
-            }
+        self.drain();
+        self.check_errors();
+        match self.dma_rx.read(buffer) {
+            0 => None,
+            count => Some(count),
         }
-        None
     }
 
     pub fn write(&mut self, buffer: &[u8]) {
@@ -86,4 +306,141 @@ impl<D: UartDevice, P: ValidUartPinout<D>> Console<D, P> {
             self.buffer.put(uin)
         }
     }
+
+    /// Write, waiting for room rather than dropping anything unless the UART
+    /// takes nothing for `timeout_ms`, as when CTS holds it off. Then all
+    /// buffered output is dropped along with the rest of `buffer`.
+    pub fn write_all(&mut self, buffer: &[u8], timer: &Timer, timeout_ms: u64) {
+        for uin in buffer {
+            if !self.drain_until(|buffer| !buffer.full(), timer, timeout_ms) {
+                return;
+            }
+            self.buffer.put(uin)
+        }
+    }
+
+    // Count and clear any receive error flags.
+    fn check_errors(&mut self) {
+        let status = unsafe { read_volatile(self.status as *const u32) };
+        if status == 0 {
+            return;
+        }
+        unsafe { write_volatile(self.status as *mut u32, 0) };
+        let errors = &mut self.errors;
+        for (bit, count) in [
+            (OVERRUN_ERROR, &mut errors.overrun),
+            (FRAMING_ERROR, &mut errors.framing),
+            (PARITY_ERROR, &mut errors.parity),
+            (BREAK_ERROR, &mut errors.breaks),
+        ] {
+            if status & bit != 0 {
+                *count = count.saturating_add(1);
+            }
+        }
+    }
+
+    /// Errors counted since the last call, starting the counts again.
+    pub fn take_errors(&mut self) -> Errors {
+        core::mem::take(&mut self.errors)
+    }
+
+    /// Wait until all buffered output has been handed to the UART, dropping
+    /// it if the UART takes nothing for `timeout_ms`.
+    pub fn flush(&mut self, timer: &Timer, timeout_ms: u64) {
+        self.drain_until(Buffer::empty, timer, timeout_ms);
+    }
+
+    // Drain until `done`, dropping all buffered output if the UART takes
+    // nothing for `timeout_ms`. Returns false if it was dropped.
+    fn drain_until(&mut self, done: fn(&Buffer) -> bool, timer: &Timer, timeout_ms: u64) -> bool {
+        let mut since = timer.get_counter();
+        while !done(&self.buffer) {
+            if self.drain() {
+                since = timer.get_counter();
+            } else if (timer.get_counter() - since).to_millis() >= timeout_ms {
+                self.buffer.clear();
+                return false;
+            }
+        }
+        true
+    }
+
+    // Move as much buffered output into the UART FIFO as it will take,
+    // returns true if any was.
+    fn drain(&mut self) -> bool {
+        let mut moved = false;
+        // Only drop a byte from the buffer once the UART has accepted it.
+        while let Some(out) = self.buffer.peek() {
+            match self.uart.as_ref().unwrap().write_raw(&[out]) {
+                Ok(_) => {
+                    self.buffer.get();
+                    moved = true;
+                }
+                Err(_) => break,
+            }
+        }
+        moved
+    }
+
+    /// Switch to one of the supported baud rates, returns false leaving the
+    /// UART untouched for any other rate.
+    pub fn set_baud(&mut self, baud: u32) -> bool {
+        if !BAUD_RATES.contains(&baud) {
+            return false;
+        }
+        self.baud = baud;
+        self.reconfigure();
+        true
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Switch the character framing, keeping the baud rate.
+    pub fn set_format(&mut self, format: Format) {
+        self.format = format;
+        self.reconfigure();
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Switch RTS/CTS flow control, the pins must already be handed to the
+    /// UART. RTS follows the receive FIFO, which the DMA keeps near empty, so
+    /// it is CTS holding back output that matters.
+    pub fn set_flow(&mut self, flow: bool) {
+        self.flow = flow;
+        self.apply_flow();
+    }
+
+    pub fn flow(&self) -> bool {
+        self.flow
+    }
+
+    // Enabling the UART sets flow control from the pin types, which have none.
+    fn apply_flow(&self) {
+        let control = self.control as *mut u32;
+        unsafe {
+            let bits = read_volatile(control) & !(CTS_ENABLE | RTS_ENABLE);
+            match self.flow {
+                true => write_volatile(control, bits | CTS_ENABLE | RTS_ENABLE),
+                false => write_volatile(control, bits),
+            }
+        }
+    }
+
+    // Apply the baud rate and framing, the UART is briefly disabled.
+    fn reconfigure(&mut self) {
+        let uart = self.uart.take().unwrap().disable();
+        let config = config(self.baud, self.format);
+        self.uart = Some(uart.enable(config, self.frequency).unwrap());
+        self.apply_flow();
+    }
+
+    /// True when a further write would be dropped.
+    pub fn full(&self) -> bool {
+        self.buffer.full()
+    }
 }
@@ -0,0 +1,58 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::pac::IO_BANK0;
+
+use crate::gpio::GPIO_COUNT;
+
+/// Eight pins to each interrupt register, four bits to a pin.
+const PINS_PER_REGISTER: usize = 8;
+const EDGE_HIGH: u32 = 0b1000;
+
+/// Counts rising edges on inputs, for flow meters and the like. Shared
+/// between `IO_IRQ_BANK0`, which counts them, and the command path.
+pub struct Counter {
+    counts: [u32; GPIO_COUNT],
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter {
+            counts: [0; GPIO_COUNT],
+        }
+    }
+
+    /// Start counting from zero on a pin, which must already be an input.
+    pub fn enable(&mut self, pin: u8) {
+        let (register, bit) = edge_bit(pin);
+        let bank = unsafe { &*IO_BANK0::ptr() };
+        // Forget any edge latched before counting began.
+        bank.intr[register].write(|w| unsafe { w.bits(bit) });
+        bank.proc0_inte[register].modify(|r, w| unsafe { w.bits(r.bits() | bit) });
+        self.counts[pin as usize] = 0;
+    }
+
+    /// Edges since the last take, resetting the count.
+    pub fn take(&mut self, pin: u8) -> u32 {
+        core::mem::take(&mut self.counts[pin as usize])
+    }
+
+    /// Count and acknowledge every pending edge, for the interrupt handler.
+    pub fn on_interrupt(&mut self) {
+        let bank = unsafe { &*IO_BANK0::ptr() };
+        for pin in 0..GPIO_COUNT as u8 {
+            let (register, bit) = edge_bit(pin);
+            if bank.proc0_ints[register].read().bits() & bit != 0 {
+                bank.intr[register].write(|w| unsafe { w.bits(bit) });
+                self.counts[pin as usize] = self.counts[pin as usize].wrapping_add(1);
+            }
+        }
+    }
+}
+
+// Interrupt register and rising edge bit for a pin.
+fn edge_bit(pin: u8) -> (usize, u32) {
+    let pin = pin as usize;
+    let shift = (pin % PINS_PER_REGISTER) * 4;
+    (pin / PINS_PER_REGISTER, EDGE_HIGH << shift)
+}
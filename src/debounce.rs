@@ -0,0 +1,76 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::timer::Instant;
+
+use crate::gpio::GPIO_COUNT;
+
+/// Settles bouncy inputs such as switches, a pin's level only changes once
+/// the new level has held for the pin's window.
+pub struct Debounce {
+    /// Window in milliseconds, 0 for pins read as they are.
+    windows: [u16; GPIO_COUNT],
+    stable: [bool; GPIO_COUNT],
+    /// A level differing from the stable one, and when it was first seen.
+    changing: [Option<(bool, Instant)>; GPIO_COUNT],
+    /// Settled rising edges, counted in place of the raw ones.
+    rises: [u32; GPIO_COUNT],
+}
+
+impl Debounce {
+    pub fn new() -> Debounce {
+        Debounce {
+            windows: [0; GPIO_COUNT],
+            stable: [false; GPIO_COUNT],
+            changing: [None; GPIO_COUNT],
+            rises: [0; GPIO_COUNT],
+        }
+    }
+
+    /// Debounce a pin over `ms`, 0 stops, starting settled at `level`.
+    pub fn set(&mut self, pin: u8, ms: u16, level: bool) {
+        let pin = pin as usize;
+        self.windows[pin] = ms;
+        self.stable[pin] = level;
+        self.changing[pin] = None;
+        self.rises[pin] = 0;
+    }
+
+    pub fn window(&self, pin: u8) -> u16 {
+        self.windows.get(pin as usize).copied().unwrap_or(0)
+    }
+
+    /// Feed a sample of a debounced pin, as often as possible.
+    pub fn update(&mut self, pin: u8, level: bool, now: Instant) {
+        let pin = pin as usize;
+        if level == self.stable[pin] {
+            self.changing[pin] = None;
+            return;
+        }
+        match self.changing[pin] {
+            Some((changing, since)) if changing == level => {
+                if (now - since).to_millis() >= self.windows[pin] as u64 {
+                    self.stable[pin] = level;
+                    self.changing[pin] = None;
+                    if level {
+                        self.rises[pin] = self.rises[pin].wrapping_add(1);
+                    }
+                }
+            }
+            _ => self.changing[pin] = Some((level, now)),
+        }
+    }
+
+    /// The settled level, None if the pin isn't debounced.
+    pub fn level(&self, pin: u8) -> Option<bool> {
+        match self.window(pin) {
+            0 => None,
+            _ => Some(self.stable[pin as usize]),
+        }
+    }
+
+    /// Settled rising edges since the last take, resetting the count.
+    pub fn take_rises(&mut self, pin: u8) -> u32 {
+        core::mem::take(&mut self.rises[pin as usize])
+    }
+}
@@ -1,110 +1,991 @@
-use core::{fmt, fmt::Write};
-use heapless::String;
+use core::fmt;
+use fugit::TimerInstantU64;
+use heapless::{String, Vec};
+
+use crate::text;
+
+/// Microsecond timestamp, matching the HAL timer.
+pub type Instant = TimerInstantU64<1_000_000>;
+
+/// Default time in milliseconds a partial command may sit idle.
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+
+/// Longest command kept for recall, a full default line with its letter and
+/// terminator. Longer lines from a larger decoder are not recalled.
+const RECALL_SIZE: usize = 68;
+
+/// Start and end of a binary protocol frame.
+const STX: u8 = 0x02;
+const ETX: u8 = 0x03;
 
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum Commands {
     Status,
+    StatusJson,
     Valve,
+    ValveAll,
+    ValvePulse,
     Led,
+    Brightness,
+    Breathe,
+    Morse,
+    Solid,
+    Help,
+    Version,
+    Id,
+    I2c,
+    Temperature,
+    Input,
+    Output,
+    Bias,
+    Analog,
+    Pwm,
+    Servo,
+    Pixel,
+    OneWire,
+    Count,
+    CountRead,
+    System,
 }
 
 impl fmt::Display for Commands {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Commands::Status => write!(f, "Status"),
+            Commands::StatusJson => write!(f, "StatusJson"),
             Commands::Led => write!(f, "Led"),
             Commands::Valve => write!(f, "Valve"),
+            Commands::ValveAll => write!(f, "ValveAll"),
+            Commands::ValvePulse => write!(f, "ValvePulse"),
+            Commands::Brightness => write!(f, "Brightness"),
+            Commands::Breathe => write!(f, "Breathe"),
+            Commands::Morse => write!(f, "Morse"),
+            Commands::Solid => write!(f, "Solid"),
+            Commands::Help => write!(f, "Help"),
+            Commands::Version => write!(f, "Version"),
+            Commands::Id => write!(f, "Id"),
+            Commands::I2c => write!(f, "I2c"),
+            Commands::Temperature => write!(f, "Temperature"),
+            Commands::Input => write!(f, "Input"),
+            Commands::Output => write!(f, "Output"),
+            Commands::Bias => write!(f, "Bias"),
+            Commands::Analog => write!(f, "Analog"),
+            Commands::Pwm => write!(f, "Pwm"),
+            Commands::Servo => write!(f, "Servo"),
+            Commands::Pixel => write!(f, "Pixel"),
+            Commands::OneWire => write!(f, "OneWire"),
+            Commands::Count => write!(f, "Count"),
+            Commands::CountRead => write!(f, "CountRead"),
+            Commands::System => write!(f, "System"),
         }
     }
 }
 
+/// Outcome of a command, numbered for hosts that ask for it on each response.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Status {
+    Ok = 0,
+    UnknownCommand = 1,
+    BadTarget = 2,
+    Overflow = 3,
+    Timeout = 4,
+    Checksum = 5,
+    Framing = 6,
+    BadTerminator = 7,
+    BadValue = 8,
+    /// The hardware on the target didn't answer.
+    NoResponse = 9,
+    /// A valve switched again sooner than `!minswitch` allows.
+    Throttled = 10,
+    /// Valves and PWM are held off by `!estop` until `!clear`.
+    Stopped = 11,
+}
+
+impl Commands {
+    /// The command introduced by a byte at the start of a command, ignoring case.
+    pub fn from_byte(c: u8) -> Option<Commands> {
+        let c = c.to_ascii_lowercase();
+        Commands::all()
+            .iter()
+            .copied()
+            .find(|cmd| cmd.letter() == c)
+    }
+
+    /// Byte that starts the command, the led effects follow an `l`.
+    pub fn letter(&self) -> u8 {
+        match self {
+            Commands::Status => b's',
+            // `sj`, a `j` straight after the `s`.
+            Commands::StatusJson => b's',
+            Commands::Valve => b'v',
+            // `v*`, a `*` in place of the valve.
+            Commands::ValveAll => b'v',
+            // `vp`, a `p` ahead of the valve.
+            Commands::ValvePulse => b'v',
+            Commands::Led => b'l',
+            Commands::Brightness => b'b',
+            Commands::Breathe => b'f',
+            Commands::Morse => b'm',
+            Commands::Solid => b'o',
+            Commands::Help => b'h',
+            // `?v`, the `?` introduces device information requests.
+            Commands::Version => b'?',
+            Commands::Id => b'i',
+            // `i` alone, `id` is the unique id.
+            Commands::I2c => b'i',
+            Commands::Temperature => b't',
+            Commands::Input => b'g',
+            Commands::Output => b'w',
+            Commands::Bias => b'p',
+            Commands::Analog => b'a',
+            Commands::Pwm => b'm',
+            Commands::Servo => b'o',
+            Commands::Pixel => b'n',
+            // `1w`, one-wire.
+            Commands::OneWire => b'1',
+            Commands::Count => b'c',
+            // `cr`, an `r` ahead of the pin.
+            Commands::CountRead => b'c',
+            Commands::System => b'!',
+        }
+    }
+
+    /// Syntax summary, one short line per form of the command.
+    pub fn help(&self) -> &'static [&'static str] {
+        match self {
+            Commands::Status => &[
+                "s           report status",
+                "sj          everything as one {key:value} line,",
+                "            after the plain status line",
+            ],
+            // Listed under the status command.
+            Commands::StatusJson => &[],
+            Commands::Valve => &[
+                "v<n> <0|1>  close or open valve n",
+                "v<n> <0-255> duty of a valve in pwm mode",
+                "v<n>?       query valve n, p<duty> in pwm",
+                "v*<mask>    set every valve, bit n is valve n",
+                "vp<n> <ms>  open valve n for ms",
+            ],
+            // Listed under the valve command.
+            Commands::ValveAll | Commands::ValvePulse => &[],
+            Commands::Led => &[
+                "l[n:]<ms>   blink led n, 0 is off",
+                "l[n:]f<ms>  breathe over ms",
+                "l[n:]m<num> blink num in Morse",
+                "l[n:]on     led on, loff for off",
+                "l[n:]?      query blink rate",
+            ],
+            Commands::Brightness => &[
+                "b[n:]<0-255> led brightness",
+                "b[n:]?      query brightness",
+            ],
+            // The led effects are listed under the led command.
+            Commands::Breathe | Commands::Morse | Commands::Solid => &[],
+            Commands::Help => &["h           this help"],
+            Commands::Version => &["?v          firmware version"],
+            // I2C is listed with id, which shares its letter.
+            Commands::Id => &[
+                "id          board unique id",
+                "i           scan i2c bus, hex addresses",
+                "iw<addr><bytes> i2c write, all hex",
+                "ir<addr><len> i2c read, len 1-10 in hex",
+            ],
+            Commands::I2c => &[],
+            Commands::Temperature => &["t           chip temperature, milli C"],
+            Commands::Input => &["g<pin>      read gpio pin"],
+            Commands::Output => &["w<pin> <0|1> drive gpio pin low or high"],
+            Commands::Bias => &["p<pin> <0-2> float, pull up or pull down"],
+            Commands::Analog => &["a<0-2>      read adc channel, mV"],
+            Commands::Pwm => &[
+                "m<pin> <0-255> 1 kHz pwm duty",
+                "            to one decimal place, m2 50.5",
+            ],
+            Commands::Servo => &[
+                "o<pin> <0-180.0> servo angle, 50 Hz",
+                "            pins on a pwm slice share its rate,",
+                "            2,3,18,19 and 4,5 and 16,17",
+            ],
+            Commands::Pixel => &["n<i><rrggbb> set pixel i colour, hex"],
+            Commands::OneWire => &["1w<pin>     ds18b20 temperature, milli C"],
+            Commands::Count => &[
+                "c<pin>      count rising edges from 0",
+                "cr<pin>     read and reset the count",
+            ],
+            // Listed under the count command.
+            Commands::CountRead => &[],
+            Commands::System => &[
+                "!boot       reboot to usb bootloader",
+                "!reset      restart the firmware",
+                "!nowatchdog stop the watchdog, for debugging",
+                "!bridge     pass through to the uart until",
+                "            !bridge or three ESC",
+                "!bridge1    the same for uart1, uart1 builds",
+                "!baud <rate> uart 9600, 19200, 38400, 57600",
+                "            or 115200 baud",
+                "!flow <0|1> uart rts/cts, cts on gpio2 and",
+                "            rts on gpio3, none to query",
+                "!uarterr [1] uart receive errors since last",
+                "            asked, overrun framing parity break",
+                "!usbdrop    usb input lost to a full queue",
+                "            since last asked",
+                "!uartcfg <8N1> uart data bits 5-8, parity",
+                "            N, E or O, stop bits 1 or 2,",
+                "            none to query",
+                "!heartbeat <ms> status every ms, 0 stops",
+                "!verbose <0|1> describe each response,",
+                "            and unknown commands, not ?<byte>",
+                "!codes <0|1> end response lines with :<status>",
+                "!quiet <0|1> no banner as a terminal opens",
+                "            the usb port, kept by !save",
+                "!seq <0|1>  lead response lines with the #<n>",
+                "            that led the request, #0 unasked",
+                "!echo <0|1> echo typed characters, up",
+                "            arrow repeats the last command",
+                "!echo <text> answers with the text, to",
+                "            check the link",
+                "!minswitch <n> <ms> refuse to switch valve",
+                "            n again within ms, VT<n>, 0 stops",
+                "!valveinvert <n> [0|1] valve n is open",
+                "            while its pin is low, kept by !save",
+                "!valvestats [reset] times each valve has",
+                "            opened or closed, kept by !save",
+                "!valvemode <n> [pwm|digital] how valve n",
+                "            is driven, none to query",
+                "!estop      close every valve, stop pwm",
+                "            and refuse both until !clear",
+                "!clear      accept valve and pwm again",
+                "!failsafe <0|1> valves to their safe state",
+                "            when the usb port closes",
+                "!safe <mask> valves open when safe, bit n",
+                "            is valve n, default all closed",
+                "!deadman <ms> close every valve if no",
+                "            command arrives for ms, 0 stops",
+                "!selftest   check the temperature sensor,",
+                "            toggle the onboard led and, with",
+                "            gpio0 jumpered to gpio1, the uart",
+                "!spi <hex>  spi0 transfer, up to 29 bytes,",
+                "            answers SPI<hex> with those read",
+                "            miso 16, cs 17, sck 18, mosi 19",
+                "!spicfg <mode> <hz> spi mode 0-3 and clock,",
+                "            none to query",
+                "!step <0|1> <count> <us> set DIR on gpio5",
+                "            then pulse STEP on gpio4, us apart,",
+                "            for at most half a second",
+                "!watch <pin> send EV<pin><level> when an",
+                "            input changes, with its level now",
+                "!unwatch <pin> stop sending its changes",
+                "!debounce <pin> <ms> g and c only see a",
+                "            level held for ms, 0 stops",
+                "!ledraw <0|1|auto> hold the onboard led pin,",
+                "            auto returns it to its mode",
+                "!vsys       supply voltage in millivolts",
+                "!crc <start> <len> crc32 of flash from",
+                "            offset start, 0x for hex",
+                "!hist       the last 16 commands that",
+                "            succeeded, oldest first",
+                "!dstate     what each port's decoder is",
+                "            waiting for",
+                "!clocks     system, peripheral and usb Hz",
+                "!mem        free stack now, and never used",
+                "!show       resend the pixels",
+                "!save       keep settings over a reset",
+                "!defaults   forget saved settings",
+            ],
+        }
+    }
+
+    /// Every command that can start a line, in help order.
+    pub fn all() -> &'static [Commands] {
+        &[
+            Commands::Status,
+            Commands::Valve,
+            Commands::Led,
+            Commands::Brightness,
+            Commands::Help,
+            Commands::Version,
+            Commands::Id,
+            Commands::Temperature,
+            Commands::Input,
+            Commands::Output,
+            Commands::Bias,
+            Commands::Analog,
+            Commands::Pwm,
+            Commands::Servo,
+            Commands::Pixel,
+            Commands::OneWire,
+            Commands::Count,
+            Commands::System,
+        ]
+    }
+}
+
 enum DecodeState {
     Command,
     Target,
+    TargetDigits,
     NextValue,
     Value,
+    HexValue,
+    /// The tenths digit after a `.` in a PWM or servo value.
+    Fraction,
+    Query,
+    Switch,
+    Letter,
+    /// Straight after an `s`, otherwise just like `Command`.
+    AfterStatus,
+    /// The digits of a `#<n>` ahead of a command.
+    Sequence,
+    Line,
+    /// After an ESC while echoing, which may start an arrow key.
+    Escape,
+    /// After ESC `[`.
+    Csi,
 }
 
-pub enum DecodeResult {
+/// Line and message text is held in `N` byte strings, 64 unless chosen.
+pub enum DecodeResult<const N: usize = 64> {
     None,
-    Text(String<64>),
     Command(Commands, u8, u16),
+    Query(Commands, u8),
+    /// A `!` command line, without the `!`.
+    System(String<N>),
+    /// The rest of the line following a command's letters.
+    Line(Commands, String<N>),
+    /// A byte accepted without completing anything, to show it was typed.
+    Echo(u8),
+    /// The last completed command, to be run again on the up arrow.
+    Recall(Vec<u8, RECALL_SIZE>),
+    Error(Status, String<N>),
 }
 
-pub struct Decoder {
+/// Decodes ASCII commands, collecting `!` and other lines of up to `N` bytes.
+pub struct Decoder<const N: usize = 64> {
     state: DecodeState,
     target: u8,
     value: u16,
+    digits: u8,
     command: Commands,
+    /// Idle time in milliseconds before a partial command is abandoned, 0 never.
+    pub timeout: u64,
+    active: bool,
+    last: Option<Instant>,
+    terminator: Option<u8>,
+    line: String<N>,
+    /// Hand back each accepted byte as `DecodeResult::Echo`, and recall the
+    /// last command on the up arrow.
+    pub echo: bool,
+    /// Explain an unknown command rather than answer a bare `?<byte>`.
+    pub verbose: bool,
+    typed: Vec<u8, RECALL_SIZE>,
+    recall: Vec<u8, RECALL_SIZE>,
+    sequence: Option<u16>,
+    /// None until a digit follows the decimal point.
+    tenths: Option<u8>,
 }
 
-impl Decoder {
-    pub fn new() -> Decoder {
+impl<const N: usize> Decoder<N> {
+    pub fn new() -> Decoder<N> {
         Decoder {
             state: DecodeState::Command,
             target: 0,
             value: 0,
+            digits: 0,
             command: Commands::Status,
+            timeout: DEFAULT_TIMEOUT_MS,
+            active: false,
+            last: None,
+            terminator: None,
+            line: String::new(),
+            echo: false,
+            verbose: false,
+            typed: Vec::new(),
+            recall: Vec::new(),
+            sequence: None,
+            tenths: None,
         }
     }
-    pub fn run(&mut self, c: &u8) -> DecodeResult {
-        match self.state {
-            DecodeState::Command => match c {
-                b's' | b'S' => return DecodeResult::Command(Commands::Status, 0, 0),
-                b'v' | b'V' => {
-                    self.command = Commands::Valve;
-                    self.state = DecodeState::Target
+
+    /// A decoder that only completes commands on `terminator`, rejecting any
+    /// other unexpected byte rather than treating it as the end of the value.
+    pub fn new_with_terminator(terminator: u8) -> Decoder<N> {
+        Decoder {
+            terminator: Some(terminator),
+            ..Decoder::new()
+        }
+    }
+
+    /// Abandon a partial command once no input has arrived for `timeout`.
+    pub fn tick(&mut self, now: Instant) -> DecodeResult<N> {
+        let last = *self.last.get_or_insert(now);
+        if self.active {
+            self.active = false;
+            self.last = Some(now);
+        } else if self.timeout > 0 && (now - last).to_millis() > self.timeout {
+            match self.state {
+                DecodeState::Command | DecodeState::AfterStatus => {}
+                // A lone ESC, nothing was being typed.
+                DecodeState::Escape | DecodeState::Csi => self.state = DecodeState::Command,
+                _ => {
+                    self.sequence = None;
+                    return self.error(Status::Timeout, format_args!("Err: timeout\r\n"));
                 }
-                b'l' | b'L' => {
-                    self.command = Commands::Led;
-                    self.state = DecodeState::NextValue
+            }
+        }
+        DecodeResult::None
+    }
+
+    pub fn run(&mut self, c: &u8) -> DecodeResult<N> {
+        let escaped = self.escaping();
+        let result = self.step(c);
+        let escaping = escaped || self.escaping();
+        self.record(c, &result, escaping);
+        match result {
+            DecodeResult::None if self.echo && !escaping => DecodeResult::Echo(*c),
+            result => result,
+        }
+    }
+
+    fn escaping(&self) -> bool {
+        matches!(self.state, DecodeState::Escape | DecodeState::Csi)
+    }
+
+    // Keep the bytes of the command being typed, and of the last one to
+    // complete for recall.
+    fn record(&mut self, c: &u8, result: &DecodeResult<N>, escaping: bool) {
+        match result {
+            DecodeResult::Command(..)
+            | DecodeResult::Query(..)
+            | DecodeResult::System(_)
+            | DecodeResult::Line(..) => {
+                self.recall = match self.typed.push(*c) {
+                    Ok(()) => self.typed.clone(),
+                    // Too long to replay faithfully.
+                    Err(_) => Vec::new(),
+                };
+                self.typed.clear();
+            }
+            DecodeResult::None if !escaping && !matches!(self.state, DecodeState::Command) => {
+                // Anything too long is dropped once it completes.
+                let _ = self.typed.push(*c);
+            }
+            _ => self.typed.clear(),
+        }
+    }
+
+    /// What the decoder is waiting for, to show why input isn't acting.
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            DecodeState::Command | DecodeState::AfterStatus => "command",
+            DecodeState::Target | DecodeState::TargetDigits => "target",
+            DecodeState::NextValue
+            | DecodeState::Value
+            | DecodeState::HexValue
+            | DecodeState::Fraction => "value",
+            DecodeState::Query => "query",
+            DecodeState::Switch => "switch",
+            DecodeState::Letter => "letter",
+            DecodeState::Sequence => "sequence",
+            DecodeState::Line => "line",
+            DecodeState::Escape | DecodeState::Csi => "escape",
+        }
+    }
+
+    /// The `#<n>` that led the command just decoded, if it had one.
+    pub fn take_sequence(&mut self) -> Option<u16> {
+        self.sequence.take()
+    }
+
+    /// Tenths following the whole value of the command just decoded, 0 for
+    /// commands without a decimal point.
+    pub fn take_tenths(&mut self) -> u8 {
+        self.tenths.take().unwrap_or(0)
+    }
+
+    // ESC abandons the command, and may begin an arrow key while echoing.
+    fn cancel(&mut self) {
+        self.sequence = None;
+        self.state = if self.echo {
+            DecodeState::Escape
+        } else {
+            DecodeState::Command
+        };
+    }
+
+    fn step(&mut self, c: &u8) -> DecodeResult<N> {
+        self.active = true;
+        match self.state {
+            // The status is sent as soon as the `s` arrives, so `sj` can only
+            // follow it with everything else.
+            DecodeState::AfterStatus if matches!(c, b'j' | b'J') => {
+                self.state = DecodeState::Command;
+                return DecodeResult::Command(Commands::StatusJson, 0, 0);
+            }
+            DecodeState::AfterStatus => {
+                self.state = DecodeState::Command;
+                return self.step(c);
+            }
+            DecodeState::Command if *c == 27 => self.cancel(),
+            // `#<n>` numbers the command that follows, for matching up replies.
+            DecodeState::Command if *c == b'#' => {
+                self.sequence = Some(0);
+                self.state = DecodeState::Sequence
+            }
+            DecodeState::Sequence => match c {
+                27 => self.cancel(),
+                b'0'..=b'9' => {
+                    let digit = (c - b'0') as u16;
+                    match self
+                        .sequence
+                        .and_then(|n| n.checked_mul(10))
+                        .and_then(|n| n.checked_add(digit))
+                    {
+                        Some(n) => self.sequence = Some(n),
+                        None => {
+                            self.sequence = None;
+                            return self.error(
+                                Status::Overflow,
+                                format_args!("Err: sequence too large\r\n"),
+                            );
+                        }
+                    }
                 }
-                // ignore control codes.
-                0..=31 => {}
+                // The command letter.
                 _ => {
-                    let mut text: String<64> = String::new();
-                    writeln!(text, "Err: unrecognised '{}'\r", c).unwrap();
-                    return DecodeResult::Text(text);
+                    self.state = DecodeState::Command;
+                    return self.step(c);
                 }
             },
+            DecodeState::Command => match Commands::from_byte(*c) {
+                Some(Commands::Status) => {
+                    self.state = DecodeState::AfterStatus;
+                    return DecodeResult::Command(Commands::Status, 0, 0);
+                }
+                Some(command @ (Commands::Help | Commands::Temperature)) => {
+                    return DecodeResult::Command(command, 0, 0)
+                }
+                // `!` commands are words, collected up to the end of the line,
+                // as is the pixel index and colour.
+                Some(command @ (Commands::System | Commands::Pixel)) => {
+                    self.command = command;
+                    self.line.clear();
+                    self.state = DecodeState::Line
+                }
+                // Two letter commands.
+                Some(command @ (Commands::Version | Commands::Id | Commands::OneWire)) => {
+                    self.command = command;
+                    self.state = DecodeState::Letter
+                }
+                Some(command) => {
+                    self.command = command;
+                    self.target = 0;
+                    self.tenths = None;
+                    self.state = if matches!(
+                        command,
+                        Commands::Valve
+                            | Commands::Input
+                            | Commands::Output
+                            | Commands::Bias
+                            | Commands::Analog
+                            | Commands::Pwm
+                            | Commands::Servo
+                            | Commands::Count
+                    ) {
+                        DecodeState::Target
+                    } else {
+                        DecodeState::NextValue
+                    }
+                }
+                None => match c {
+                    // ignore control codes.
+                    0..=31 => {}
+                    // `;` separates batched commands, the value terminator is
+                    // already consumed so only commands without a value reach here.
+                    b';' => {}
+                    // A stable token for hosts, the explanation for people.
+                    _ if self.verbose => {
+                        return self.error(
+                            Status::UnknownCommand,
+                            format_args!("Err: unrecognised '{}'\r\n", c),
+                        );
+                    }
+                    _ => {
+                        return self.error(Status::UnknownCommand, format_args!("?{}\r\n", c));
+                    }
+                },
+            },
             DecodeState::Target => match c {
                 // Esc cancel command
-                27 => self.state = DecodeState::Command,
+                27 => self.cancel(),
                 b'0'..=b'9' => {
                     self.target = c - b'0';
+                    self.digits = 1;
+                    self.state = DecodeState::TargetDigits
+                }
+                // `v*<mask>` sets every valve at once.
+                b'*' if self.command == Commands::Valve => {
+                    self.command = Commands::ValveAll;
                     self.state = DecodeState::NextValue
                 }
+                // `vp<valve> <ms>` opens the valve for a while.
+                b'p' | b'P' if self.command == Commands::Valve => {
+                    self.command = Commands::ValvePulse
+                }
+                // `cr<pin>` reads the count back.
+                b'r' | b'R' if self.command == Commands::Count => {
+                    self.command = Commands::CountRead
+                }
                 // ignore control codes.
                 0..=31 => {}
                 _ => {
-                    let mut text: String<64> = String::new();
-                    writeln!(text, "Err: bad target '{}'\r", c).unwrap();
-                    self.state = DecodeState::Command;
-                    return DecodeResult::Text(text);
+                    return self.error(
+                        Status::BadTarget,
+                        format_args!("Err: bad target '{}'\r\n", c),
+                    );
+                }
+            },
+            DecodeState::TargetDigits => match c {
+                // Esc cancel command
+                27 => self.cancel(),
+                b'0'..=b'9' => {
+                    match self
+                        .target
+                        .checked_mul(10)
+                        .and_then(|t| t.checked_add(c - b'0'))
+                    {
+                        Some(target) => {
+                            self.target = target;
+                            self.digits = self.digits.saturating_add(1);
+                        }
+                        None => {
+                            return self.error(
+                                Status::BadTarget,
+                                format_args!("Err: bad target '{}'\r\n", c),
+                            );
+                        }
+                    }
+                }
+                // Backspace or delete removes the last digit, then the target.
+                8 | 127 => {
+                    self.target /= 10;
+                    self.digits -= 1;
+                    if self.digits == 0 {
+                        self.state = DecodeState::Target
+                    }
                 }
+                b'?' => self.state = DecodeState::Query,
+                // Reads take no value, so the target is the whole command.
+                _ if matches!(
+                    self.command,
+                    Commands::Input
+                        | Commands::Analog
+                        | Commands::OneWire
+                        | Commands::Count
+                        | Commands::CountRead
+                ) =>
+                {
+                    let result = DecodeResult::Command(self.command, self.target, 0);
+                    return self.terminate(c, result);
+                }
+                // Any other byte separates the target from the value.
+                _ => self.state = DecodeState::NextValue,
             },
             DecodeState::NextValue => match c {
                 // Esc cancel command
-                27 => self.state = DecodeState::Command,
+                27 => self.cancel(),
                 b'0'..=b'9' => {
                     self.value = (c - b'0') as u16;
+                    self.digits = 1;
                     self.state = DecodeState::Value
                 }
+                b'?' => self.state = DecodeState::Query,
+                // `lf<period>` selects the breathing effect.
+                b'f' | b'F' if self.command == Commands::Led => self.command = Commands::Breathe,
+                // `lm<number>` blinks the number out in Morse.
+                b'm' | b'M' if self.command == Commands::Led => self.command = Commands::Morse,
+                // `lon` / `loff` hold the led steady.
+                b'o' | b'O' if self.command == Commands::Led => {
+                    self.command = Commands::Solid;
+                    self.value = 0;
+                    self.state = DecodeState::Switch
+                }
                 _ => {}
             },
             DecodeState::Value => match c {
                 // Esc cancel command
-                27 => self.state = DecodeState::Command,
+                27 => self.cancel(),
+                b'0'..=b'9' => return self.accumulate(10, c - b'0'),
+                // Backspace or delete removes the last digit.
+                8 | 127 => {
+                    self.value /= 10;
+                    self.digits -= 1;
+                    if self.digits == 0 {
+                        self.state = DecodeState::NextValue
+                    }
+                }
+                // A leading zero followed by 'x' switches to hex entry.
+                b'x' | b'X' if self.digits == 1 && self.value == 0 => {
+                    self.digits = 0;
+                    self.state = DecodeState::HexValue
+                }
+                b'?' => self.state = DecodeState::Query,
+                // PWM duty and servo angles may take one decimal place.
+                b'.' if matches!(self.command, Commands::Pwm | Commands::Servo) => {
+                    self.state = DecodeState::Fraction
+                }
+                // `l<target>:<value>` addresses an led other than the onboard one.
+                b':' if self.command != Commands::Valve => {
+                    if self.value <= u8::MAX as u16 {
+                        self.target = self.value as u8;
+                        self.state = DecodeState::NextValue
+                    } else {
+                        let value = self.value;
+                        return self.error(
+                            Status::BadTarget,
+                            format_args!("Err: bad target '{}'\r\n", value),
+                        );
+                    }
+                }
+                _ => {
+                    let result = DecodeResult::Command(self.command, self.target, self.value);
+                    return self.terminate(c, result);
+                }
+            },
+            DecodeState::HexValue => match c {
+                // Esc cancel command
+                27 => self.cancel(),
+                b'0'..=b'9' => return self.accumulate(16, c - b'0'),
+                b'a'..=b'f' => return self.accumulate(16, c - b'a' + 10),
+                b'A'..=b'F' => return self.accumulate(16, c - b'A' + 10),
+                // Backspace or delete removes the last digit, then the prefix.
+                8 | 127 => {
+                    if self.digits == 0 {
+                        self.digits = 1;
+                        self.state = DecodeState::Value
+                    } else {
+                        self.value /= 16;
+                        self.digits -= 1;
+                    }
+                }
+                _ => {
+                    let result = DecodeResult::Command(self.command, self.target, self.value);
+                    return self.terminate(c, result);
+                }
+            },
+            DecodeState::Fraction => match c {
+                // Esc cancel command
+                27 => self.cancel(),
+                b'0'..=b'9' if self.tenths.is_none() => self.tenths = Some(c - b'0'),
                 b'0'..=b'9' => {
-                    self.value = self.value * 10 + (c - b'0') as u16;
-                    self.state = DecodeState::Value
+                    self.tenths = None;
+                    return self.error(
+                        Status::BadValue,
+                        format_args!("Err: one decimal place only\r\n"),
+                    );
+                }
+                // Backspace or delete removes the tenths, then the point.
+                8 | 127 => {
+                    if self.tenths.take().is_none() {
+                        self.state = DecodeState::Value
+                    }
                 }
                 _ => {
+                    let result = DecodeResult::Command(self.command, self.target, self.value);
+                    return self.terminate(c, result);
+                }
+            },
+            DecodeState::Switch => match c {
+                // Esc cancel command
+                27 => self.cancel(),
+                b'n' | b'N' => self.value = 1,
+                b'f' | b'F' => self.value = 0,
+                _ => {
+                    let result = DecodeResult::Command(self.command, self.target, self.value);
+                    return self.terminate(c, result);
+                }
+            },
+            DecodeState::Letter => match (self.command, c) {
+                // Esc cancel command
+                (_, 27) => self.cancel(),
+                (Commands::Version, b'v' | b'V') | (Commands::Id, b'd' | b'D') => {
                     self.state = DecodeState::Command;
-                    return DecodeResult::Command(self.command, self.target, self.value);
+                    return DecodeResult::Command(self.command, 0, 0);
+                }
+                // `iw` and `ir` take a line of hex, kept with its `w` or `r`.
+                (Commands::Id, b'w' | b'W' | b'r' | b'R') => {
+                    self.command = Commands::I2c;
+                    self.line.clear();
+                    self.line.push(c.to_ascii_lowercase() as char).unwrap();
+                    self.state = DecodeState::Line
+                }
+                // `1w` takes a pin.
+                (Commands::OneWire, b'w' | b'W') => {
+                    self.target = 0;
+                    self.state = DecodeState::Target
+                }
+                // A bare `i` scans the I2C bus.
+                (Commands::Id, 0..=31) => {
+                    return self.terminate(c, DecodeResult::Command(Commands::I2c, 0, 0));
+                }
+                _ => {
+                    let first = self.command.letter() as char;
+                    return self.error(
+                        Status::UnknownCommand,
+                        format_args!("Err: unrecognised '{}{}'\r\n", first, *c as char),
+                    );
+                }
+            },
+            DecodeState::Line => match c {
+                // Esc cancel command
+                27 => self.cancel(),
+                // Backspace or delete removes the last character.
+                8 | 127 => {
+                    self.line.pop();
+                }
+                // Any other control code ends the line.
+                0..=31 => {
+                    let result = match self.command {
+                        Commands::System => DecodeResult::System(self.line.clone()),
+                        command => DecodeResult::Line(command, self.line.clone()),
+                    };
+                    return self.terminate(c, result);
+                }
+                _ => {
+                    if self.line.push(*c as char).is_err() {
+                        return self
+                            .error(Status::Overflow, format_args!("Err: line too long\r\n"));
+                    }
+                }
+            },
+            DecodeState::Escape => {
+                self.state = if *c == b'[' {
+                    DecodeState::Csi
+                } else {
+                    DecodeState::Command
+                }
+            }
+            // Up arrow, other keys are ignored.
+            DecodeState::Csi => {
+                self.state = DecodeState::Command;
+                if *c == b'A' && !self.recall.is_empty() {
+                    return DecodeResult::Recall(self.recall.clone());
+                }
+            }
+            DecodeState::Query => match c {
+                // Esc cancel command
+                27 => self.cancel(),
+                _ => {
+                    let result = DecodeResult::Query(self.command, self.target);
+                    return self.terminate(c, result);
                 }
             },
         }
         DecodeResult::None
     }
+
+    // Add a digit to the value, abandoning the command if it would overflow.
+    fn accumulate(&mut self, radix: u16, digit: u8) -> DecodeResult<N> {
+        match self
+            .value
+            .checked_mul(radix)
+            .and_then(|v| v.checked_add(digit as u16))
+        {
+            Some(value) => {
+                self.value = value;
+                self.digits = self.digits.saturating_add(1);
+                DecodeResult::None
+            }
+            None => self.error(Status::Overflow, format_args!("Err: value too large\r\n")),
+        }
+    }
+
+    // Complete the pending command, if the byte is an acceptable terminator.
+    fn terminate(&mut self, c: &u8, result: DecodeResult<N>) -> DecodeResult<N> {
+        match self.terminator {
+            Some(terminator) if *c != terminator => self.error(
+                Status::BadTerminator,
+                format_args!("Err: bad terminator '{}'\r\n", c),
+            ),
+            _ => {
+                self.state = DecodeState::Command;
+                result
+            }
+        }
+    }
+
+    // Report an error and return to waiting for a command.
+    fn error(&mut self, code: Status, message: fmt::Arguments) -> DecodeResult<N> {
+        self.state = DecodeState::Command;
+        error(code, message)
+    }
+}
+
+/// Decoder for the framed binary protocol:
+/// `STX target cmd value_hi value_lo checksum ETX`, where the checksum is the
+/// low byte of the sum of target, cmd and value bytes.
+pub struct BinaryDecoder {
+    frame: [u8; 5],
+    count: usize,
+    receiving: bool,
+}
+
+impl BinaryDecoder {
+    pub fn new() -> BinaryDecoder {
+        BinaryDecoder {
+            frame: [0; 5],
+            count: 0,
+            receiving: false,
+        }
+    }
+
+    pub fn run(&mut self, c: &u8) -> DecodeResult {
+        if !self.receiving {
+            // Discard anything between frames.
+            if *c == STX {
+                self.receiving = true;
+                self.count = 0;
+            }
+            return DecodeResult::None;
+        }
+        if self.count < self.frame.len() {
+            self.frame[self.count] = *c;
+            self.count += 1;
+            return DecodeResult::None;
+        }
+        self.receiving = false;
+        let [target, cmd, value_hi, value_lo, checksum] = self.frame;
+        if *c != ETX {
+            return error(Status::Framing, format_args!("Err: bad frame\r\n"));
+        }
+        let sum = target
+            .wrapping_add(cmd)
+            .wrapping_add(value_hi)
+            .wrapping_add(value_lo);
+        if sum != checksum {
+            return error(Status::Checksum, format_args!("Err: bad checksum\r\n"));
+        }
+        // The led effects have their own bytes as there is no sub-command,
+        // upper case reaches the top level command sharing the letter.
+        let command = match cmd {
+            b'f' => Commands::Breathe,
+            b'm' => Commands::Morse,
+            b'o' => Commands::Solid,
+            _ => match Commands::from_byte(cmd) {
+                Some(command) => command,
+                None => {
+                    return error(
+                        Status::UnknownCommand,
+                        format_args!("Err: unrecognised '{}'\r\n", cmd),
+                    )
+                }
+            },
+        };
+        DecodeResult::Command(command, target, u16::from_be_bytes([value_hi, value_lo]))
+    }
+}
+
+fn error<const N: usize>(code: Status, message: fmt::Arguments) -> DecodeResult<N> {
+    let mut text: String<N> = String::new();
+    text::write(&mut text, message);
+    DecodeResult::Error(code, text)
 }
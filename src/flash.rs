@@ -0,0 +1,190 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::rom_data;
+use core::ptr::{read_volatile, write_volatile};
+
+// SSI registers, RP2040 datasheet 4.10.13.
+const SSI_CTRLR0: *mut u32 = 0x1800_0000 as *mut u32;
+const SSI_CTRLR1: *mut u32 = 0x1800_0004 as *mut u32;
+const SSI_SSIENR: *mut u32 = 0x1800_0008 as *mut u32;
+const SSI_SR: *const u32 = 0x1800_0028 as *const u32;
+const SSI_DR0: *mut u32 = 0x1800_0060 as *mut u32;
+// SR receive FIFO not empty.
+const SSI_SR_RFNE: u32 = 0x08;
+// CTRLR0 transfer mode EEPROM read.
+const SSI_TMOD_EEPROM: u32 = 0x300;
+
+// Flash command to read the 64-bit unique ID, followed by 4 dummy bytes.
+const CMD_READ_UNIQUE_ID: u32 = 0x4b;
+const UNIQUE_ID_DUMMY_BYTES: u32 = 4;
+
+// Start of flash as seen through XIP, where the second stage boot loader lives.
+const XIP_BASE: *const u32 = 0x1000_0000 as *const u32;
+
+/// Smallest unit flash can be programmed in.
+pub const PAGE_SIZE: usize = 256;
+// Smallest unit flash can be erased in, and the command erasing one.
+const SECTOR_SIZE: u32 = 4096;
+const SECTOR_ERASE: u8 = 0x20;
+/// Size of the flash chip on the Pico.
+pub const FLASH_SIZE: u32 = 2048 * 1024;
+// The last sector of the flash holds the saved configuration, memory.x keeps
+// the firmware out of it.
+const CONFIG_OFFSET: u32 = FLASH_SIZE - SECTOR_SIZE;
+// Reflected CRC-32 polynomial, as used by zlib and Ethernet.
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+// ROM routines, looked up while flash is still readable.
+struct Rom {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+impl Rom {
+    fn lookup() -> Rom {
+        Rom {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        }
+    }
+}
+
+// Run `f` with interrupts disabled and a RAM copy of the boot loader, which
+// re-enables fast XIP reads afterwards.
+fn without_xip<R>(f: impl FnOnce(&Rom, unsafe extern "C" fn()) -> R) -> R {
+    let mut boot2 = [0u32; 64];
+    let rom = Rom::lookup();
+    cortex_m::interrupt::free(|_| unsafe {
+        core::ptr::copy_nonoverlapping(XIP_BASE, boot2.as_mut_ptr(), boot2.len());
+        // Thumb code, so call with the low bit set.
+        let enter_xip: unsafe extern "C" fn() =
+            core::mem::transmute((boot2.as_ptr() as *const u8).add(1));
+        f(&rom, enter_xip)
+    })
+}
+
+/// Read the unique ID of the flash chip, which identifies the board.
+///
+/// Takes a few microseconds with interrupts disabled, flash is unusable while
+/// the command runs.
+pub fn unique_id() -> u64 {
+    let mut id = [0u8; 8];
+    without_xip(|rom, enter_xip| unsafe {
+        read_unique_id(rom, enter_xip, id.as_mut_ptr(), id.len() as u32)
+    });
+    u64::from_be_bytes(id)
+}
+
+/// The first page of the configuration sector, all 0xff once erased.
+pub fn read_config() -> [u8; PAGE_SIZE] {
+    let mut page = [0u8; PAGE_SIZE];
+    let base = unsafe { (XIP_BASE as *const u8).add(CONFIG_OFFSET as usize) };
+    for (i, byte) in page.iter_mut().enumerate() {
+        *byte = unsafe { read_volatile(base.add(i)) };
+    }
+    page
+}
+
+/// Carry `crc` on over `len` bytes of flash from `offset`, None if that runs
+/// past the end of flash. Start from `!0` and invert the final result for the
+/// usual CRC-32, which lets a long range be taken in pieces.
+///
+/// Works a bit at a time rather than from a table, tens of milliseconds per
+/// 64 KB.
+pub fn crc32(crc: u32, offset: u32, len: u32) -> Option<u32> {
+    if offset.checked_add(len)? > FLASH_SIZE {
+        return None;
+    }
+    let base = unsafe { (XIP_BASE as *const u8).add(offset as usize) };
+    let mut crc = crc;
+    for i in 0..len as usize {
+        crc ^= unsafe { read_volatile(base.add(i)) } as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (CRC32_POLYNOMIAL & mask);
+        }
+    }
+    Some(crc)
+}
+
+/// Replace the saved configuration with `page`.
+///
+/// Takes tens of milliseconds with interrupts disabled.
+pub fn write_config(page: &[u8; PAGE_SIZE]) {
+    without_xip(|rom, enter_xip| unsafe {
+        program_config(rom, enter_xip, page.as_ptr(), page.len())
+    });
+}
+
+/// Erase the saved configuration, so the defaults are used from next boot.
+pub fn erase_config() {
+    without_xip(|rom, enter_xip| unsafe { program_config(rom, enter_xip, core::ptr::null(), 0) });
+}
+
+/// # Safety
+///
+/// Nothing may execute from or otherwise access flash while this runs, it
+/// must be called with interrupts disabled. Only touches RAM, ROM and the SSI
+/// so avoid anything here that might call out to code in flash.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn read_unique_id(rom: &Rom, enter_xip: unsafe extern "C" fn(), out: *mut u8, len: u32) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+
+    write_volatile(SSI_SSIENR, 0);
+    write_volatile(SSI_CTRLR0, read_volatile(SSI_CTRLR0) | SSI_TMOD_EEPROM);
+    write_volatile(SSI_CTRLR1, UNIQUE_ID_DUMMY_BYTES + len - 1);
+    write_volatile(SSI_SSIENR, 1);
+    write_volatile(SSI_DR0, CMD_READ_UNIQUE_ID);
+
+    let mut count = 0;
+    while count < UNIQUE_ID_DUMMY_BYTES + len {
+        while read_volatile(SSI_SR) & SSI_SR_RFNE == 0 {}
+        let byte = read_volatile(SSI_DR0) as u8;
+        if count >= UNIQUE_ID_DUMMY_BYTES {
+            write_volatile(out.add((count - UNIQUE_ID_DUMMY_BYTES) as usize), byte);
+        }
+        count += 1;
+    }
+
+    // flash_enter_cmd_xip does not reset CTRLR1, XIP breaks unless we do.
+    write_volatile(SSI_SSIENR, 0);
+    write_volatile(SSI_CTRLR1, 0);
+
+    (rom.flash_flush_cache)();
+    enter_xip();
+}
+
+/// # Safety
+///
+/// As for `read_unique_id`, and `data` must point to `len` bytes of RAM.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn program_config(
+    rom: &Rom,
+    enter_xip: unsafe extern "C" fn(),
+    data: *const u8,
+    len: usize,
+) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    (rom.flash_range_erase)(
+        CONFIG_OFFSET,
+        SECTOR_SIZE as usize,
+        SECTOR_SIZE,
+        SECTOR_ERASE,
+    );
+    if len > 0 {
+        (rom.flash_range_program)(CONFIG_OFFSET, data, len);
+    }
+    (rom.flash_flush_cache)();
+    enter_xip();
+}
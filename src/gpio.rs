@@ -0,0 +1,200 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::gpio::{
+    DynPin, DynPinMode, DYN_FLOATING_INPUT, DYN_FUNCTION_PWM, DYN_FUNCTION_SPI, DYN_FUNCTION_UART,
+    DYN_PULL_DOWN_INPUT, DYN_PULL_UP_INPUT, DYN_PUSH_PULL_OUTPUT,
+};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// GPIO numbers run 0 - 29 on the RP2040.
+pub const GPIO_COUNT: usize = 30;
+
+/// Pull resistor used while a pin is an input, numbered as in the protocol.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Bias {
+    Float,
+    PullUp,
+    PullDown,
+}
+
+impl Bias {
+    pub fn from_code(code: u16) -> Option<Bias> {
+        match code {
+            0 => Some(Bias::Float),
+            1 => Some(Bias::PullUp),
+            2 => Some(Bias::PullDown),
+            _ => None,
+        }
+    }
+
+    fn input_mode(&self) -> DynPinMode {
+        match self {
+            Bias::Float => DYN_FLOATING_INPUT,
+            Bias::PullUp => DYN_PULL_UP_INPUT,
+            Bias::PullDown => DYN_PULL_DOWN_INPUT,
+        }
+    }
+}
+
+/// Spare pins available for general use, indexed by GPIO number.
+pub struct Gpio {
+    pins: [Option<DynPin>; GPIO_COUNT],
+    bias: [Bias; GPIO_COUNT],
+    // Spare pins a peripheral has claimed, out of general use until released.
+    reserved: u32,
+}
+
+impl Gpio {
+    /// Pins left out are reserved for other functions and cannot be used.
+    pub fn new<const N: usize>(spare: [DynPin; N]) -> Gpio {
+        let mut pins: [Option<DynPin>; GPIO_COUNT] = Default::default();
+        for pin in spare {
+            let number = pin.id().num as usize;
+            pins[number] = Some(pin);
+        }
+        Gpio {
+            pins,
+            bias: [Bias::Float; GPIO_COUNT],
+            reserved: 0,
+        }
+    }
+
+    /// Sample a pin, switching it to an input with its bias first if needed.
+    /// Returns None if the pin is reserved.
+    pub fn read(&mut self, number: u8) -> Option<bool> {
+        let mode = self.bias.get(number as usize)?.input_mode();
+        let pin = self.pin_mut(number)?;
+        if pin.mode() != mode {
+            pin.try_into_mode(mode).unwrap();
+        }
+        Some(pin.is_high().unwrap())
+    }
+
+    /// Drive a pin, switching it to an output first if needed. Returns false
+    /// if the pin is reserved.
+    pub fn write(&mut self, number: u8, high: bool) -> bool {
+        match self.pin_mut(number) {
+            Some(pin) => {
+                if !matches!(pin.mode(), DynPinMode::Output(_)) {
+                    pin.into_push_pull_output();
+                }
+                if high {
+                    pin.set_high().unwrap();
+                } else {
+                    pin.set_low().unwrap();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Choose the pull resistor for a pin, taking effect on its next read.
+    /// Returns false if the pin is reserved.
+    pub fn set_bias(&mut self, number: u8, bias: Bias) -> bool {
+        match self.pin_mut(number) {
+            Some(_) => {
+                self.bias[number as usize] = bias;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// A pin for a driver that manages its mode itself, None if reserved.
+    pub fn pin_mut(&mut self, number: u8) -> Option<&mut DynPin> {
+        if self.is_reserved(number) {
+            return None;
+        }
+        self.pins.get_mut(number as usize)?.as_mut()
+    }
+
+    /// True for a spare pin claimed by a peripheral until it is released.
+    pub fn is_reserved(&self, number: u8) -> bool {
+        (number as usize) < GPIO_COUNT && self.reserved & 1 << number != 0
+    }
+
+    /// True while a spare pin is switched to its PWM function.
+    pub fn is_pwm(&self, number: u8) -> bool {
+        matches!(self.pins.get(number as usize), Some(Some(pin)) if pin.mode() == DYN_FUNCTION_PWM)
+    }
+
+    /// Hand a pin to its PWM slice. Returns false if the pin is reserved.
+    pub fn claim_pwm(&mut self, number: u8) -> bool {
+        !self.is_reserved(number) && self.claim(number, DYN_FUNCTION_PWM)
+    }
+
+    /// Hand a pin to the SPI block and reserve it until `release`. Returns
+    /// false if the pin is reserved for anything else.
+    pub fn claim_spi(&mut self, number: u8) -> bool {
+        self.reserve(number, DYN_FUNCTION_SPI)
+    }
+
+    /// Hand a pin to a UART and reserve it until `release`. Returns false if
+    /// the pin is reserved for anything else.
+    pub fn claim_uart(&mut self, number: u8) -> bool {
+        self.reserve(number, DYN_FUNCTION_UART)
+    }
+
+    /// Reserve a pin as an output for a peripheral, such as a chip select,
+    /// driven with `drive`. Returns false if the pin is reserved for anything
+    /// else.
+    pub fn claim_output(&mut self, number: u8) -> bool {
+        self.reserve(number, DYN_PUSH_PULL_OUTPUT)
+    }
+
+    /// Drive a pin reserved with `claim_output`, returns false for any other.
+    pub fn drive(&mut self, number: u8, high: bool) -> bool {
+        if !self.is_reserved(number) {
+            return false;
+        }
+        match self.pins[number as usize].as_mut() {
+            Some(pin) if pin.mode() == DYN_PUSH_PULL_OUTPUT => {
+                if high {
+                    pin.set_high().unwrap();
+                } else {
+                    pin.set_low().unwrap();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Return a reserved pin to general use, as an input.
+    pub fn release(&mut self, number: u8) {
+        if self.is_reserved(number) {
+            self.reserved &= !(1 << number);
+            let mode = self.bias[number as usize].input_mode();
+            if let Some(pin) = self.pin_mut(number) {
+                pin.try_into_mode(mode).unwrap();
+            }
+        }
+    }
+
+    // Claim a pin for `function` and keep it from general use. A pin already
+    // reserved for the same function may be claimed again.
+    fn reserve(&mut self, number: u8, function: DynPinMode) -> bool {
+        if self.is_reserved(number) {
+            return matches!(&self.pins[number as usize], Some(pin) if pin.mode() == function);
+        }
+        if !self.claim(number, function) {
+            return false;
+        }
+        self.reserved |= 1 << number;
+        true
+    }
+
+    fn claim(&mut self, number: u8, function: DynPinMode) -> bool {
+        match self.pins.get_mut(number as usize) {
+            Some(Some(pin)) => {
+                if pin.mode() != function {
+                    pin.try_into_mode(function).unwrap();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,98 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::{
+    gpio::{
+        bank0::{Gpio14, Gpio15},
+        FunctionI2C, Pin,
+    },
+    i2c::{self, I2C},
+    pac::{I2C1, SIO},
+};
+use embedded_hal::blocking::i2c::{Read, Write};
+use heapless::Vec;
+
+/// Addresses probed by a scan, the rest are reserved by the I2C spec.
+const FIRST_ADDRESS: u8 = 0x08;
+const LAST_ADDRESS: u8 = 0x77;
+const ADDRESS_COUNT: usize = (LAST_ADDRESS - FIRST_ADDRESS + 1) as usize;
+
+/// Longest read or write passed through to a device.
+pub const MAX_TRANSFER: usize = 16;
+
+/// SDA and SCL in the SIO input register.
+const LINES: u32 = 1 << 14 | 1 << 15;
+
+/// I2C1 with SDA on GPIO14 and SCL on GPIO15.
+pub type Bus = I2C<I2C1, (Pin<Gpio14, FunctionI2C>, Pin<Gpio15, FunctionI2C>)>;
+
+/// Why a transfer failed.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Error {
+    /// A line is held low.
+    Busy,
+    /// The device did not acknowledge, or the transfer was abandoned.
+    Nak,
+    /// A reserved address, or a length the HAL refuses.
+    Invalid,
+}
+
+impl From<i2c::Error> for Error {
+    fn from(error: i2c::Error) -> Error {
+        match error {
+            i2c::Error::Abort(_) => Error::Nak,
+            _ => Error::Invalid,
+        }
+    }
+}
+
+pub struct I2cBus {
+    i2c: Bus,
+}
+
+impl I2cBus {
+    pub fn new(i2c: Bus) -> I2cBus {
+        I2cBus { i2c }
+    }
+
+    /// True while both lines idle high. Without pull-ups they never do, and
+    /// the HAL would wait forever for a transfer to finish.
+    pub fn idle(&self) -> bool {
+        // The pads can be read whichever function drives them.
+        let levels = unsafe { (*SIO::ptr()).gpio_in.read().bits() };
+        levels & LINES == LINES
+    }
+
+    /// Addresses of every device that acknowledges, None if the bus is held
+    /// low.
+    pub fn scan(&mut self) -> Option<Vec<u8, ADDRESS_COUNT>> {
+        if !self.idle() {
+            return None;
+        }
+        let mut found = Vec::new();
+        for address in FIRST_ADDRESS..=LAST_ADDRESS {
+            // The HAL refuses zero-length writes, a one byte read is as harmless.
+            let mut byte = [0u8];
+            if self.i2c.read(address, &mut byte).is_ok() {
+                found.push(address).unwrap();
+            }
+        }
+        Some(found)
+    }
+
+    /// Send `bytes` to the device at `address`.
+    pub fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Error> {
+        if !self.idle() {
+            return Err(Error::Busy);
+        }
+        Ok(self.i2c.write(address, bytes)?)
+    }
+
+    /// Fill `buffer` from the device at `address`.
+    pub fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Error> {
+        if !self.idle() {
+            return Err(Error::Busy);
+        }
+        Ok(self.i2c.read(address, buffer)?)
+    }
+}
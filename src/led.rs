@@ -1,40 +1,301 @@
 // Use alias bsp so we can switch boards at a single location
 use rp_pico as bsp;
 
-use bsp::hal::{
-    gpio::{Output, Pin, PinId, PushPull},
-    timer::Instant,
-};
-use embedded_hal::digital::v2::{OutputPin, StatefulOutputPin};
-
-pub struct Led<I: PinId> {
-    pin: Pin<I, Output<PushPull>>,
+use bsp::hal::{gpio::DynPin, timer::Instant};
+use core::fmt::{self, Debug};
+use embedded_hal::digital::v2::StatefulOutputPin;
+use heapless::String;
+
+use crate::decoder::{Commands, DecodeResult, Status};
+use crate::text::{put, putln};
+
+/// Number of leds addressable by the protocol, target 0 is the onboard led.
+pub const LED_COUNT: usize = 4;
+
+/// Software PWM period in microseconds (~1kHz).
+const PWM_PERIOD_US: u64 = 1000;
+
+/// On and off time of the LED blink acknowledging an accepted command.
+pub const ACK_PULSE_MS: u64 = 50;
+
+/// Length of a Morse dot in milliseconds.
+const MORSE_UNIT_MS: u64 = 150;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+    Blink,
+    Breathe,
+    Solid,
+    Morse,
+    Pulse,
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Blink => write!(f, "Blink"),
+            Mode::Breathe => write!(f, "Breathe"),
+            Mode::Solid => write!(f, "Solid"),
+            Mode::Morse => write!(f, "Morse"),
+            Mode::Pulse => write!(f, "Pulse"),
+        }
+    }
+}
+
+/// A snapshot of an led for status reports, shown as `Lv<on>r<rate>m<mode>`.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct LedStatus {
+    pub on: bool,
+    pub rate: u64,
+    /// The mode settled in, see [`Led::steady_mode`].
+    pub mode: Mode,
+}
+
+impl fmt::Display for LedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Lv{}r{}m{}", self.on as i32, self.rate, self.mode)
+    }
+}
+
+/// Blink rate in milliseconds a led starts with.
+pub const DEFAULT_RATE: u64 = 500;
+
+pub struct Led<P> {
+    pin: P,
+    pub mode: Mode,
     pub rate: u64,
-    last: Instant,
+    pub brightness: u8,
+    pub period: u64,
+    /// Holds the pin on or off, `run` leaves the mode alone while set.
+    pub manual_override: Option<bool>,
+    last: Option<Instant>,
+    morse: Morse,
+    pulse: Pulse,
+}
+
+// Playback position within a queued Morse message.
+struct Morse {
+    message: String<16>,
+    index: usize,
+    symbol: usize,
+    gap: bool,
+    wait: u64,
+    previous: Mode,
+}
+
+// Remaining blinks of a one-shot acknowledgement.
+struct Pulse {
+    remaining: u8,
+    on_ms: u64,
+    off_ms: u64,
+    lit: bool,
+    wait: u64,
+    previous: Mode,
 }
 
-impl<I: PinId> Led<I> {
-    pub fn new(pin: Pin<I, Output<PushPull>>) -> Led<I> {
-        let rate: u64 = 500;
+impl<P: StatefulOutputPin> Led<P>
+where
+    P::Error: Debug,
+{
+    pub fn new(pin: P) -> Led<P> {
         Led {
             pin,
-            rate,
-            last: Instant::from_ticks(0),
+            mode: Mode::Blink,
+            rate: DEFAULT_RATE,
+            brightness: 255,
+            period: 0,
+            manual_override: None,
+            last: None,
+            morse: Morse {
+                message: String::new(),
+                index: 0,
+                symbol: 0,
+                gap: false,
+                wait: 0,
+                previous: Mode::Blink,
+            },
+            pulse: Pulse {
+                remaining: 0,
+                on_ms: 0,
+                off_ms: 0,
+                lit: false,
+                wait: 0,
+                previous: Mode::Blink,
+            },
         }
     }
 
+    /// Blink at the given rate in milliseconds, 0 turns the led off.
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+        self.mode = Mode::Blink;
+    }
+
+    /// Drive the led at a fixed brightness, 0 is off and 255 is fully on.
+    pub fn set_brightness(&mut self, brightness: u8) {
+        self.brightness = brightness;
+        self.mode = Mode::Solid;
+    }
+
+    /// Hold the led steadily on or off.
+    pub fn set_solid(&mut self, on: bool) {
+        self.set_brightness(if on { 255 } else { 0 });
+    }
+
+    /// The mode the led settles in once any acknowledgement pulse is done.
+    pub fn steady_mode(&self) -> Mode {
+        if self.mode == Mode::Pulse {
+            self.pulse.previous
+        } else {
+            self.mode
+        }
+    }
+
+    /// Fade up and down over the given period in milliseconds, 0 is solid on.
+    pub fn set_breathe(&mut self, period: u64) {
+        if period == 0 {
+            self.set_brightness(255);
+        } else {
+            self.period = period;
+            self.mode = Mode::Breathe;
+            // Start the ramp from dark.
+            self.last = None;
+        }
+    }
+
+    /// Blink out a message in Morse, then return to the current mode.
+    pub fn morse(&mut self, message: String<16>) {
+        if self.mode != Mode::Morse {
+            self.morse.previous = self.mode;
+        }
+        self.morse.message = message;
+        self.morse.index = 0;
+        self.morse.symbol = 0;
+        self.morse.gap = false;
+        self.morse.wait = 0;
+        self.mode = Mode::Morse;
+        self.last = None;
+    }
+
+    /// Blink `count` times, then return to the current mode.
+    pub fn pulse(&mut self, count: u8, on_ms: u64, off_ms: u64) {
+        if count == 0 {
+            return;
+        }
+        if self.mode != Mode::Pulse {
+            self.pulse.previous = self.mode;
+        }
+        self.pulse.remaining = count;
+        self.pulse.on_ms = on_ms;
+        self.pulse.off_ms = off_ms;
+        self.pulse.lit = false;
+        self.pulse.wait = 0;
+        self.mode = Mode::Pulse;
+        self.last = None;
+    }
+
     pub fn run(&mut self, now: &Instant) {
-        // blink the led
+        match self.manual_override {
+            Some(true) => return self.on(),
+            Some(false) => return self.off(),
+            None => {}
+        }
+        // Time the first interval from the first call rather than from boot.
+        let last = *self.last.get_or_insert(*now);
+        match self.mode {
+            Mode::Blink => self.blink(now, last),
+            Mode::Breathe => self.breathe(now, last),
+            Mode::Solid => self.pwm(now, self.brightness),
+            Mode::Morse => self.morse_run(now, last),
+            Mode::Pulse => self.pulse_run(now, last),
+        }
+    }
+
+    fn pulse_run(&mut self, now: &Instant, last: Instant) {
+        if (*now - last).to_millis() < self.pulse.wait {
+            return;
+        }
+        if self.pulse.lit {
+            self.off();
+            self.pulse.lit = false;
+            self.pulse.remaining -= 1;
+            self.pulse.wait = self.pulse.off_ms;
+        } else if self.pulse.remaining > 0 {
+            self.on();
+            self.pulse.lit = true;
+            self.pulse.wait = self.pulse.on_ms;
+        } else {
+            self.mode = self.pulse.previous;
+        }
+        self.last = Some(*now);
+    }
+
+    fn morse_run(&mut self, now: &Instant, last: Instant) {
+        if (*now - last).to_millis() < self.morse.wait {
+            return;
+        }
+        match self.morse.step() {
+            Some((on, units)) => {
+                if on {
+                    self.on();
+                } else {
+                    self.off();
+                }
+                self.morse.wait = units * MORSE_UNIT_MS;
+                self.last = Some(*now);
+            }
+            None => {
+                self.off();
+                self.mode = self.morse.previous;
+            }
+        }
+    }
+
+    fn blink(&mut self, now: &Instant, last: Instant) {
         if self.rate > 0 {
-            if (*now - self.last).to_millis() > self.rate {
+            if (*now - last).to_millis() > self.rate {
                 self.toggle();
-                self.last = *now
+                self.last = Some(*now)
             }
         } else {
             self.off();
         }
     }
 
+    // Triangular ramp from off to full brightness and back once per period.
+    fn breathe(&mut self, now: &Instant, start: Instant) {
+        let t = (*now - start).to_millis() % self.period;
+        let ramp = if 2 * t < self.period {
+            2 * t
+        } else {
+            2 * (self.period - t)
+        };
+        let duty = (ramp * 255 / self.period).min(255) as u8;
+        self.pwm(now, duty);
+    }
+
+    // Set the pin for this point in the software PWM period.
+    fn pwm(&mut self, now: &Instant, duty: u8) {
+        let phase = now.ticks() % PWM_PERIOD_US;
+        if phase * 255 < duty as u64 * PWM_PERIOD_US {
+            self.on();
+        } else {
+            self.off();
+        }
+    }
+
+    /// True while the led is software PWM dimmed, so must run continuously.
+    pub fn dimmed(&self) -> bool {
+        if self.manual_override.is_some() {
+            return false;
+        }
+        match self.mode {
+            Mode::Breathe => true,
+            Mode::Solid => self.brightness != 0 && self.brightness != 255,
+            _ => false,
+        }
+    }
+
     fn on(&mut self) {
         self.pin.set_high().unwrap();
     }
@@ -47,6 +308,14 @@ impl<I: PinId> Led<I> {
         self.pin.is_set_high().unwrap()
     }
 
+    pub fn status(&self) -> LedStatus {
+        LedStatus {
+            on: self.is_on(),
+            rate: self.rate,
+            mode: self.steady_mode(),
+        }
+    }
+
     pub fn toggle(&mut self) {
         if self.is_on() {
             self.off()
@@ -55,3 +324,227 @@ impl<I: PinId> Led<I> {
         }
     }
 }
+
+pub struct LedBank<P = DynPin> {
+    onboard: Led<P>,
+    external: [Option<Led<P>>; LED_COUNT - 1],
+}
+
+impl LedBank<DynPin> {
+    pub fn new(mut onboard: DynPin, external: [Option<DynPin>; LED_COUNT - 1]) -> LedBank {
+        let external = external.map(|pin| {
+            pin.map(|mut pin| {
+                pin.into_push_pull_output();
+                pin
+            })
+        });
+        onboard.into_push_pull_output();
+        LedBank::with_pins(onboard, external)
+    }
+}
+
+impl<P: StatefulOutputPin> LedBank<P>
+where
+    P::Error: Debug,
+{
+    /// A bank on pins already configured as outputs.
+    pub fn with_pins(onboard: P, external: [Option<P>; LED_COUNT - 1]) -> LedBank<P> {
+        LedBank {
+            onboard: Led::new(onboard),
+            external: external.map(|pin| pin.map(Led::new)),
+        }
+    }
+
+    pub fn onboard(&mut self) -> &mut Led<P> {
+        &mut self.onboard
+    }
+
+    /// The led for a target, None if the target has no pin assigned.
+    pub fn get(&self, target: u8) -> Option<&Led<P>> {
+        match target {
+            0 => Some(&self.onboard),
+            _ => self.external.get(target as usize - 1)?.as_ref(),
+        }
+    }
+
+    pub fn get_mut(&mut self, target: u8) -> Option<&mut Led<P>> {
+        match target {
+            0 => Some(&mut self.onboard),
+            _ => self.external.get_mut(target as usize - 1)?.as_mut(),
+        }
+    }
+
+    /// Run an led command, returning its response and outcome.
+    /// The leds' part of the command path: respond to decoder output meant
+    /// for them, None leaves anything else to the rest of it.
+    pub fn respond<const N: usize>(
+        &mut self,
+        result: &DecodeResult<N>,
+    ) -> Option<(String<64>, Status)> {
+        match *result {
+            DecodeResult::Command(
+                cmd @ (Commands::Led
+                | Commands::Breathe
+                | Commands::Morse
+                | Commands::Solid
+                | Commands::Brightness),
+                target,
+                value,
+            ) => Some(self.command(cmd, target, value)),
+            DecodeResult::Query(cmd @ (Commands::Led | Commands::Brightness), target) => {
+                Some(self.query(cmd, target))
+            }
+            _ => None,
+        }
+    }
+
+    fn query(&self, cmd: Commands, target: u8) -> (String<64>, Status) {
+        let mut text: String<64> = String::new();
+        let led = match self.get(target) {
+            Some(led) => led,
+            None if cmd == Commands::Brightness => {
+                putln!(text, "BE{}\r", target);
+                return (text, Status::BadTarget);
+            }
+            None => {
+                putln!(text, "LE{}\r", target);
+                return (text, Status::BadTarget);
+            }
+        };
+        if cmd == Commands::Brightness {
+            putln!(text, "BQ{}\r", led.brightness);
+        } else {
+            putln!(text, "LQ{}\r", led.rate);
+        }
+        (text, Status::Ok)
+    }
+
+    fn command(&mut self, cmd: Commands, target: u8, value: u16) -> (String<64>, Status) {
+        let mut text: String<64> = String::new();
+        let led = match self.get_mut(target) {
+            Some(led) => led,
+            None => {
+                putln!(text, "LE{}\r", target);
+                return (text, Status::BadTarget);
+            }
+        };
+        if cmd == Commands::Led {
+            led.set_rate(value as u64);
+        } else if cmd == Commands::Breathe {
+            led.set_breathe(value as u64);
+        } else if cmd == Commands::Solid {
+            led.set_solid(value != 0);
+        } else if cmd == Commands::Morse {
+            let mut message: String<16> = String::new();
+            put!(message, "{}", value);
+            led.morse(message);
+        } else if value <= u8::MAX as u16 {
+            led.set_brightness(value as u8);
+        } else {
+            putln!(text, "BE{}\r", target);
+            return (text, Status::BadValue);
+        }
+        self.onboard.pulse(1, ACK_PULSE_MS, ACK_PULSE_MS);
+        if cmd == Commands::Brightness {
+            putln!(text, "BA{}\r", target);
+        } else {
+            putln!(text, "LA{}\r", target);
+        }
+        (text, Status::Ok)
+    }
+
+    /// True if any led is dimmed, see [`Led::dimmed`].
+    pub fn dimmed(&self) -> bool {
+        self.onboard.dimmed() || self.external.iter().flatten().any(|led| led.dimmed())
+    }
+
+    pub fn run(&mut self, now: &Instant) {
+        self.onboard.run(now);
+        for led in self.external.iter_mut().flatten() {
+            led.run(now);
+        }
+    }
+}
+
+impl Morse {
+    // Advance to the next element, returning the led state and its length in
+    // units, or None once the message is complete.
+    fn step(&mut self) -> Option<(bool, u64)> {
+        loop {
+            let c = *self.message.as_bytes().get(self.index)?;
+            if c == b' ' {
+                // Word gap is 7 units, 3 of which followed the last letter.
+                self.index += 1;
+                return Some((false, 4));
+            }
+            let code = morse_code(c).as_bytes();
+            if self.gap {
+                self.gap = false;
+                self.symbol += 1;
+                if self.symbol >= code.len() {
+                    self.symbol = 0;
+                    self.index += 1;
+                    return Some((false, 3));
+                }
+                return Some((false, 1));
+            }
+            match code.get(self.symbol) {
+                Some(b'-') => {
+                    self.gap = true;
+                    return Some((true, 3));
+                }
+                Some(_) => {
+                    self.gap = true;
+                    return Some((true, 1));
+                }
+                // Skip characters with no Morse representation.
+                None => {
+                    self.symbol = 0;
+                    self.index += 1;
+                }
+            }
+        }
+    }
+}
+
+fn morse_code(c: u8) -> &'static str {
+    match c.to_ascii_uppercase() {
+        b'A' => ".-",
+        b'B' => "-...",
+        b'C' => "-.-.",
+        b'D' => "-..",
+        b'E' => ".",
+        b'F' => "..-.",
+        b'G' => "--.",
+        b'H' => "....",
+        b'I' => "..",
+        b'J' => ".---",
+        b'K' => "-.-",
+        b'L' => ".-..",
+        b'M' => "--",
+        b'N' => "-.",
+        b'O' => "---",
+        b'P' => ".--.",
+        b'Q' => "--.-",
+        b'R' => ".-.",
+        b'S' => "...",
+        b'T' => "-",
+        b'U' => "..-",
+        b'V' => "...-",
+        b'W' => ".--",
+        b'X' => "-..-",
+        b'Y' => "-.--",
+        b'Z' => "--..",
+        b'0' => "-----",
+        b'1' => ".----",
+        b'2' => "..---",
+        b'3' => "...--",
+        b'4' => "....-",
+        b'5' => ".....",
+        b'6' => "-....",
+        b'7' => "--...",
+        b'8' => "---..",
+        b'9' => "----.",
+        _ => "",
+    }
+}
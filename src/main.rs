@@ -1,40 +1,67 @@
-//! # Pico USB Serial Example
+//! # Pico expander
 //!
-//! Creates a USB Serial device on a Pico board, with the USB driver running in
-//! the main thread.
+//! Turns a Pico into an I/O expander driven by short commands over USB serial
+//! or the UART: valves, leds, GPIO, PWM and servos, the ADC, I2C, SPI and
+//! more, `h` lists them.
 //!
-//! This will create a USB Serial device echoing anything it receives. Incoming
-//! ASCII characters are converted to uppercase, so you can tell it is working
-//! and not just local-echo!
+//! RTIC runs USB at the highest priority, the led timer below it and the
+//! commands in a task beneath both, spawned as USB input arrives and at each
+//! led tick, which takes the queued input and returns. The USB port, leds and
+//! edge counts are shared resources, each locked only against the tasks that
+//! use it.
 //!
 //! See the `Cargo.toml` file for Copyright and license details.
 
 #![no_std]
 #![no_main]
 
+mod analog;
+mod bridge;
+mod config;
 mod console;
+mod counter;
+mod debounce;
 mod decoder;
+mod flash;
+mod gpio;
+mod i2c;
 mod led;
+mod onewire;
+#[cfg(feature = "panic-usb")]
+mod panic;
+mod pixel;
+mod pwm;
+mod spi;
+mod stack;
+mod text;
 mod usb;
+mod valve;
 
 // Use alias bsp so we can switch boards at a single location
 use rp_pico as bsp;
 
-// The macro for our start-up function
-use bsp::entry;
-
 // Ensure we halt the program on panic (if we don't mention this crate it won't
-// be linked)
+// be linked), panic-usb reports it first.
+#[cfg(not(feature = "panic-usb"))]
 use panic_halt as _;
 
 // Aliases for the Hardware Abstraction Layer, Peripheral Access Crate
 // and peripherals.
 use bsp::{
     hal::{
-        clocks,
-        clocks::Clock,
-        gpio::{FunctionUart, PinId},
+        adc::Adc,
+        clocks::{self, Clock, ClocksManager, InitError},
+        dma::DREQ_UART0_RX,
+        gpio::{
+            bank0::{Gpio0, Gpio1},
+            FunctionI2C, FunctionPio0, FunctionUart, Pin,
+        },
+        i2c::I2C,
         pac,
+        pwm::Slices,
+        rom_data,
+        spi::Spi,
+        timer::{Alarm, Alarm0, Instant},
         uart::{UartDevice, UartPeripheral, ValidUartPinout},
         usb::UsbBus as HalUsbBus,
         Sio, Timer, Watchdog,
@@ -42,147 +69,2511 @@ use bsp::{
     Pins,
 };
 
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::watchdog::{Watchdog as _, WatchdogDisable, WatchdogEnable};
+use fugit::{ExtU32, RateExtU32};
+use rtic::Mutex;
 use usb_device::class_prelude::*;
 
-use core::fmt::Write;
-use heapless::String;
+use core::{
+    fmt,
+    ops::{Deref, DerefMut},
+};
+use heapless::{HistoryBuffer, String, Vec};
 
 // Local modules.
-use console::Console;
-use decoder::{Commands, DecodeResult, Decoder};
-use led::Led;
+use analog::Analog;
+use bridge::Bridge;
+use config::Config;
+use console::{Console, DmaRx, Format, RxRing};
+use counter::Counter;
+use debounce::Debounce;
+use decoder::{BinaryDecoder, Commands, DecodeResult, Decoder, Status};
+use gpio::{Bias, Gpio, GPIO_COUNT};
+use i2c::{I2cBus, MAX_TRANSFER};
+use led::{LedBank, Mode, ACK_PULSE_MS, DEFAULT_RATE, LED_COUNT};
+use pixel::Pixels;
+use pwm::{Pwm, SERVO_MAX_ANGLE};
+use spi::SpiBus;
+use text::{put, putln};
 use usb::Usb;
+use valve::{Mode as ValveMode, Valves, VALVE_COUNT};
+
+/// Byte that must end each ASCII command, None accepts any non-digit.
+const TERMINATOR: Option<u8> = None;
+
+/// Reset the chip if the command task stops running for this long.
+const WATCHDOG_MS: u32 = 1000;
+
+/// Tries at bringing up the crystal and PLLs before giving up.
+const CLOCK_ATTEMPTS: u32 = 3;
+
+/// Cycles in each unit of the clock failure blink, about half a second on
+/// the ring oscillator the chip boots from.
+const FAILURE_BLINK_CYCLES: u32 = 3_000_000;
+
+/// Die temperatures a working sensor reports, in millidegrees Celsius.
+const SELFTEST_TEMPERATURES: core::ops::RangeInclusive<i32> = -20_000..=85_000;
+
+/// Bytes sent round a TX to RX jumper on the UART.
+const SELFTEST_LOOPBACK: &[u8] = b"\x55\xaaPX";
+
+/// Time for the loopback bytes to come back, ample at the slowest baud rate.
+const SELFTEST_LOOPBACK_MS: u32 = 10;
+
+/// Time the onboard led is held on, then off, long enough to be seen.
+const SELFTEST_LED_MS: u32 = 200;
+
+/// Stepper driver STEP and DIR inputs, on spare GPIOs clear of SPI and flow
+/// control. `uart1` builds take them for UART1.
+const STEP_PIN: u8 = 4;
+const DIR_PIN: u8 = 5;
+
+/// Longest a run of steps may take, as nothing else is done meanwhile.
+const STEP_MAX_US: u64 = 500_000;
+
+/// Onboard led blink rate in milliseconds while stopped by `!estop`.
+const ESTOP_BLINK_MS: u64 = 100;
+
+/// Accepted commands kept for `!hist`.
+const HISTORY_SIZE: usize = 16;
+
+/// Flash checksummed between watchdog feeds by `!crc`.
+const CRC_CHUNK: u32 = 64 * 1024;
+
+/// Time between led updates, short enough for blink timing.
+const TICK_US: u32 = 1000;
+
+/// Time between led updates while software PWM dims one, as each period of
+/// `led::PWM_PERIOD_US` needs a good number of them.
+const DIMMED_TICK_US: u32 = 10;
+
+/// How long to keep servicing USB so a final response reaches the host.
+const DRAIN_MS: u64 = 10;
+
+/// Longest the UART may take no output before what is queued for it is
+/// dropped, as CTS can hold it off for good.
+const UART_WRITE_MS: u64 = 100;
+
+/// Output held back while the USB buffer is full, `buffer-large` suits
+/// higher throughput.
+#[cfg(not(feature = "buffer-large"))]
+const PENDING_SIZE: usize = 256;
+#[cfg(feature = "buffer-large")]
+const PENDING_SIZE: usize = 1024;
+
+/// UART input taken each run of the command task, at most the DMA ring.
+#[cfg(not(feature = "buffer-large"))]
+const UART_BUFFER_SIZE: usize = 64;
+#[cfg(feature = "buffer-large")]
+const UART_BUFFER_SIZE: usize = 256;
+
+/// DMA channels that empty the UART receive FIFOs.
+const UART0_RX_CHANNEL: usize = 0;
+#[cfg(feature = "uart1")]
+const UART1_RX_CHANNEL: usize = 1;
+
+/// What the command task shares with the interrupt handlers, each run
+/// under a lock holding off only the tasks sharing it.
+trait Shared {
+    fn with_usb<R>(&mut self, f: impl FnOnce(&mut Usb<'static, HalUsbBus>) -> R) -> R;
+    fn with_leds<R>(&mut self, f: impl FnOnce(&mut LedBank) -> R) -> R;
+    fn with_counter<R>(&mut self, f: impl FnOnce(&mut Counter) -> R) -> R;
+}
+
+/// Run `f` on the USB port, letting the panic handler know it is in use.
+fn use_usb<R>(
+    usb: &mut Usb<'static, HalUsbBus>,
+    f: impl FnOnce(&mut Usb<'static, HalUsbBus>) -> R,
+) -> R {
+    #[cfg(feature = "panic-usb")]
+    return panic::using(usb, f);
+    #[cfg(not(feature = "panic-usb"))]
+    f(usb)
+}
+
+/// UART0 TX on GPIO0 and RX on GPIO1.
+type UartPins = (Pin<Gpio0, FunctionUart>, Pin<Gpio1, FunctionUart>);
+
+/// UART0 CTS and RTS, taken from the spare pins while flow control is on.
+const FLOW_PINS: [u8; 2] = [2, 3];
+
+/// UART1 TX on GPIO4 and RX on GPIO5.
+#[cfg(feature = "uart1")]
+type Uart1Pins = (
+    Pin<bsp::hal::gpio::bank0::Gpio4, FunctionUart>,
+    Pin<bsp::hal::gpio::bank0::Gpio5, FunctionUart>,
+);
+
+/// What an accepted command asked for.
+#[derive(Clone, Copy)]
+enum Request {
+    Set(u8, u16),
+    Query(u8),
+    /// A system or other line command, whose text isn't kept.
+    Line,
+}
 
-struct Io<'a, B: UsbBus, LP: PinId, D: UartDevice, P: ValidUartPinout<D>> {
+/// A command that succeeded, as kept for `!hist`.
+#[derive(Clone, Copy)]
+struct Accepted {
+    at: Instant,
+    cmd: Commands,
+    request: Request,
+}
+
+/// Where a command came from, and so where its response goes.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Transport {
+    Usb,
+    Uart,
+    #[cfg(feature = "uart1")]
+    Uart1,
+}
+
+/// Transports in this build, each has its own decoder.
+#[cfg(not(feature = "uart1"))]
+const TRANSPORT_COUNT: usize = 2;
+#[cfg(feature = "uart1")]
+const TRANSPORT_COUNT: usize = 3;
+
+/// Wire protocol spoken over the USB serial port.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Protocol {
+    Ascii,
+    Binary,
+}
+
+/// Clock frequencies in Hz, kept as the clocks are handed out at boot.
+#[derive(Clone, Copy)]
+struct Frequencies {
+    system: u32,
+    peripheral: u32,
+    usb: u32,
+}
+
+/// Everything the command task keeps between runs, bar what it shares.
+struct State<D: UartDevice, P: ValidUartPinout<D>> {
     timer: Timer,
-    led: Led<LP>,
+    /// When the firmware started, for uptime.
+    boot: Instant,
+    /// The latest commands that succeeded, for `!hist`.
+    history: HistoryBuffer<Accepted, HISTORY_SIZE>,
+    watchdog: Watchdog,
+    analog: Analog,
     console: Console<D, P>,
-    usb: Usb<'a, B>,
+    /// A second serial channel, with its own decoder.
+    #[cfg(feature = "uart1")]
+    console1: Console<pac::UART1, Uart1Pins>,
+    /// USB output still to send, oldest first.
+    pending: Vec<u8, PENDING_SIZE>,
+    valves: Valves<VALVE_COUNT>,
+    gpio: Gpio,
+    debounce: Debounce,
+    /// Bit n set for each pin n reporting its changes, and the level each
+    /// was last reported at.
+    watched: u32,
+    watched_levels: u32,
+    pwm: Pwm,
+    i2c: I2cBus,
+    spi: SpiBus,
+    pixels: Pixels,
+    protocol: Protocol,
+    /// The UART passing bytes to and from USB instead of decoding commands,
+    /// if any.
+    bridge: Option<Transport>,
+    /// Transport of the command being handled.
+    reply: Transport,
+    /// Describe what each response answers, for a person at a terminal.
+    verbose: bool,
+    /// Echo typed characters back, for a bare terminal.
+    echo: bool,
+    /// End each response with its numeric status.
+    codes: bool,
+    /// Say nothing as a terminal opens the USB port, for machine hosts.
+    quiet: bool,
+    /// Lead each response with the `#<n>` of its request, `#0` for those
+    /// nothing asked for.
+    numbered: bool,
+    /// The `#<n>` of the request being handled.
+    sequence: Option<u16>,
+    /// Set while a response is being sent, every line of it is framed with
+    /// the `#<n>` and status as asked for.
+    framing: Option<u16>,
+    /// What the response being sent answers, leading its first line in
+    /// verbose mode.
+    request: Option<String<64>>,
+    /// The response being sent has a line still to finish.
+    mid_line: bool,
+    /// Tenths after the decimal point of the command being handled.
+    tenths: u8,
+    /// What each decoder was waiting for after its last input, for `!dstate`.
+    decoder_states: [&'static str; TRANSPORT_COUNT],
+    /// Outcome of the command being handled.
+    status: Status,
+    /// Put the valves in their safe state when the USB host goes away.
+    failsafe: bool,
+    /// Every valve closed and PWM stopped by `!estop`, refusing both until
+    /// `!clear`.
+    estopped: bool,
+    /// Onboard led blink rate from before `!estop`, put back by `!clear`.
+    estop_rate: u64,
+    /// Valves left open in the safe state, bit n is valve n.
+    safe_valves: u16,
+    /// Shortest time in milliseconds between switches of each valve, 0 for
+    /// no limit, and when each last switched.
+    min_switch: [u32; VALVE_COUNT],
+    last_switch: [Option<Instant>; VALVE_COUNT],
+    /// Bit n set for each valve n open when switches were last noted.
+    was_open: u16,
+    /// Milliseconds without a good command before every valve closes, 0 for
+    /// never.
+    deadman: u64,
+    last_command: Instant,
+    /// Milliseconds between unprompted status reports, 0 for none.
+    heartbeat: u64,
+    last_heartbeat: Instant,
+    id: u64,
+    frequencies: Frequencies,
 }
 
-/// Entry point to our bare-metal application.
-///
-/// The `#[entry]` macro ensures the Cortex-M start-up code calls this function
-/// as soon as all global variables are initialised.
-#[entry]
-fn main() -> ! {
-    // Grab our singleton objects
-    let mut pac = pac::Peripherals::take().unwrap();
+/// The command task's state along with the resources it shares, for one run.
+struct Io<'a, S: Shared, D: UartDevice, P: ValidUartPinout<D>> {
+    /// The USB port, leds and edge counter.
+    shared: S,
+    state: &'a mut State<D, P>,
+}
 
-    // Set up the watchdog driver - needed by the clock setup code
-    let mut watchdog = Watchdog::new(pac.WATCHDOG);
+impl<S: Shared, D: UartDevice, P: ValidUartPinout<D>> Deref for Io<'_, S, D, P> {
+    type Target = State<D, P>;
 
-    // Configure the clocks generate a 125 MHz system clock
-    let clocks = clocks::init_clocks_and_plls(
-        bsp::XOSC_CRYSTAL_FREQ,
-        pac.XOSC,
-        pac.CLOCKS,
-        pac.PLL_SYS,
-        pac.PLL_USB,
-        &mut pac.RESETS,
-        &mut watchdog,
-    )
-    .ok()
-    .unwrap();
-
-    let sio = Sio::new(pac.SIO);
-    let pins = Pins::new(
-        pac.IO_BANK0,
-        pac.PADS_BANK0,
-        sio.gpio_bank0,
-        &mut pac.RESETS,
-    );
+    fn deref(&self) -> &State<D, P> {
+        self.state
+    }
+}
+
+impl<S: Shared, D: UartDevice, P: ValidUartPinout<D>> DerefMut for Io<'_, S, D, P> {
+    fn deref_mut(&mut self) -> &mut State<D, P> {
+        self.state
+    }
+}
+
+impl<S: Shared, D: UartDevice, P: ValidUartPinout<D>> Io<'_, S, D, P> {
+    /// Run `f` on the USB port, holding off its interrupt handler meanwhile.
+    fn with_usb<R>(&mut self, f: impl FnOnce(&mut Usb<'static, HalUsbBus>) -> R) -> R {
+        self.shared.with_usb(f)
+    }
+
+    /// Run `f` on the leds, holding off the `tick` task meanwhile.
+    fn with_leds<R>(&mut self, f: impl FnOnce(&mut LedBank) -> R) -> R {
+        self.shared.with_leds(f)
+    }
+
+    /// Blink the onboard led to show a command was accepted.
+    fn acknowledge(&mut self) {
+        self.with_leds(|leds| leds.onboard().pulse(1, ACK_PULSE_MS, ACK_PULSE_MS));
+    }
+
+    /// Queue output behind anything already pending, then send what fits.
+    fn usb_write(&mut self, bytes: &[u8]) {
+        let pending = &mut self.state.pending;
+        self.shared.with_usb(|usb| usb::queue(usb, pending, bytes));
+    }
+
+    /// Send pending USB output, keeping whatever the host hasn't taken yet.
+    fn flush_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let pending = &mut self.state.pending;
+        self.shared.with_usb(|usb| usb::flush(usb, pending));
+    }
+
+    /// Send a response back over the transport the command arrived on,
+    /// framing it as part of the response being sent, if any. A response may
+    /// be sent in pieces, each line ends with a piece.
+    fn send(&mut self, text: &String<64>) {
+        let sequence = match self.framing {
+            Some(sequence) if !text.is_empty() => sequence,
+            _ => return self.write(text.as_bytes()),
+        };
+        // Lead each line with the `#<n>`, and the first with what it answers
+        // if verbose.
+        let mut lead: String<64> = String::new();
+        if !self.mid_line {
+            if self.numbered {
+                put!(lead, "#{} ", sequence);
+            }
+            match self.request.take() {
+                Some(request) if self.verbose => put!(lead, "{}: ", request.trim_end()),
+                _ => {}
+            }
+        }
+        self.write(lead.as_bytes());
+        self.mid_line = !text.ends_with('\n');
+        if self.mid_line {
+            return self.write(text.as_bytes());
+        }
+        self.write(text.trim_end().as_bytes());
+        let mut end: String<64> = String::new();
+        if self.codes {
+            put!(end, ":{}", self.status as u8);
+        }
+        putln!(end, "\r");
+        self.write(end.as_bytes());
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self.reply {
+            Transport::Usb => self.usb_write(bytes),
+            Transport::Uart => {
+                self.state
+                    .console
+                    .write_all(bytes, &self.state.timer, UART_WRITE_MS)
+            }
+            #[cfg(feature = "uart1")]
+            Transport::Uart1 => {
+                self.state
+                    .console1
+                    .write_all(bytes, &self.state.timer, UART_WRITE_MS)
+            }
+        }
+    }
+
+    /// Pass a byte on to the bridged UART, dropping it if there's no room.
+    fn bridge_write(&mut self, c: u8) {
+        match self.bridge {
+            Some(Transport::Uart) => self.console.write(&[c]),
+            #[cfg(feature = "uart1")]
+            Some(Transport::Uart1) => self.console1.write(&[c]),
+            _ => {}
+        }
+    }
+
+    /// True while the bridged UART can take no more.
+    fn bridge_full(&self) -> bool {
+        match self.bridge {
+            Some(Transport::Uart) => self.console.full(),
+            #[cfg(feature = "uart1")]
+            Some(Transport::Uart1) => self.console1.full(),
+            _ => false,
+        }
+    }
+}
+
+#[rtic::app(device = crate::pac, peripherals = true, dispatchers = [TIMER_IRQ_1])]
+mod app {
+    use super::*;
+
+    #[shared]
+    struct Shared {
+        usb: Usb<'static, HalUsbBus>,
+        leds: LedBank,
+        counter: Counter,
+    }
+
+    #[local]
+    struct Local {
+        state: State<pac::UART0, UartPins>,
+        inputs: Inputs,
+        alarm: Alarm0,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        // Before the stack is in use, so `!mem` can find how deep it has been.
+        stack::paint();
+
+        // Grab our singleton objects
+        let mut pac = cx.device;
+
+        // Set up the watchdog driver - needed by the clock setup code
+        let mut watchdog = Watchdog::new(pac.WATCHDOG);
+
+        // Configure the clocks generate a 125 MHz system clock
+        let clocks = match init_clocks(
+            pac.XOSC,
+            pac.CLOCKS,
+            pac.PLL_SYS,
+            pac.PLL_USB,
+            &mut pac.RESETS,
+            &mut watchdog,
+        ) {
+            Ok(clocks) => clocks,
+            Err(_) => clock_failure(pac.IO_BANK0, pac.PADS_BANK0, pac.SIO, &mut pac.RESETS),
+        };
+        let frequencies = Frequencies {
+            system: clocks.system_clock.freq().to_Hz(),
+            peripheral: clocks.peripheral_clock.freq().to_Hz(),
+            usb: clocks.usb_clock.freq().to_Hz(),
+        };
+
+        // Read before anything else is running, flash is unavailable meanwhile.
+        let id = flash::unique_id();
+
+        let sio = Sio::new(pac.SIO);
+        let pins = Pins::new(
+            pac.IO_BANK0,
+            pac.PADS_BANK0,
+            sio.gpio_bank0,
+            &mut pac.RESETS,
+        );
+
+        // Set up the USB driver, the interrupt handler needs it to live forever.
+        let usb_bus = cortex_m::singleton!(: UsbBusAllocator<HalUsbBus> = UsbBusAllocator::new(
+            HalUsbBus::new(
+                pac.USBCTRL_REGS,
+                pac.USBCTRL_DPRAM,
+                clocks.usb_clock,
+                true,
+                &mut pac.RESETS,
+            )
+        ))
+        .unwrap();
+        // The device keeps the descriptor for good, so it must be built first.
+        let serial_number = cortex_m::singleton!(: String<16> = String::new()).unwrap();
+        put!(*serial_number, "{:016X}", id);
+        let mut usb = Usb::new(usb_bus, serial_number.as_str());
+
+        let uart = UartPeripheral::new(
+            pac.UART0,
+            (
+                // UART TX (characters sent from RP2040) on pin 1 (GPIO0)
+                pins.gpio0.into_mode::<FunctionUart>(),
+                // UART RX (characters received by RP2040) on pin 2 (GPIO1)
+                pins.gpio1.into_mode::<FunctionUart>(),
+            ),
+            &mut pac.RESETS,
+        );
+
+        let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+        let mut alarm = timer.alarm_0().unwrap();
+        alarm.enable_interrupt();
+        alarm.schedule(TICK_US.micros()).unwrap();
+
+        // External leds 1 and 2 on GPIO20 and GPIO21.
+        let mut leds = LedBank::new(
+            pins.led.into(),
+            [Some(pins.gpio20.into()), Some(pins.gpio21.into()), None],
+        );
 
-    // Set up the USB driver
-    let usb_bus = UsbBusAllocator::new(HalUsbBus::new(
-        pac.USBCTRL_REGS,
-        pac.USBCTRL_DPRAM,
-        clocks.usb_clock,
-        true,
-        &mut pac.RESETS,
-    ));
-
-    let uart = UartPeripheral::new(
-        pac.UART0,
+        watchdog.pause_on_debug(true);
+        watchdog.start(WATCHDOG_MS.millis());
+
+        // UART1 takes GPIO4 and GPIO5 from the spare pins.
+        #[cfg(feature = "uart1")]
+        let uart1 = UartPeripheral::new(
+            pac.UART1,
+            (
+                pins.gpio4.into_mode::<FunctionUart>(),
+                pins.gpio5.into_mode::<FunctionUart>(),
+            ),
+            &mut pac.RESETS,
+        );
+        // Everything not claimed is free for general use.
+        #[cfg(not(feature = "uart1"))]
+        let gpio = Gpio::new([
+            pins.gpio2.into(),
+            pins.gpio3.into(),
+            pins.gpio4.into(),
+            pins.gpio5.into(),
+            pins.gpio16.into(),
+            pins.gpio17.into(),
+            pins.gpio18.into(),
+            pins.gpio19.into(),
+        ]);
+        #[cfg(feature = "uart1")]
+        let gpio = Gpio::new([
+            pins.gpio2.into(),
+            pins.gpio3.into(),
+            pins.gpio16.into(),
+            pins.gpio17.into(),
+            pins.gpio18.into(),
+            pins.gpio19.into(),
+        ]);
+        // The UARTs share the DMA block, a channel each.
+        console::enable_dma(pac.DMA, &mut pac.RESETS);
+
+        let mut state = State {
+            watchdog,
+            // ADC0-ADC2 on GPIO26-GPIO28, VSYS on ADC3.
+            analog: Analog::new(
+                Adc::new(pac.ADC, &mut pac.RESETS),
+                (
+                    pins.gpio26.into_floating_input(),
+                    pins.gpio27.into_floating_input(),
+                    pins.gpio28.into_floating_input(),
+                ),
+                pins.voltage_monitor.into_floating_input(),
+            ),
+            console: Console::new(
+                uart,
+                clocks.peripheral_clock.freq(),
+                DmaRx::new(
+                    UART0_RX_CHANNEL,
+                    cortex_m::singleton!(: RxRing = RxRing::new()).unwrap(),
+                    unsafe { (*pac::UART0::ptr()).uartdr.as_ptr() as u32 },
+                    DREQ_UART0_RX,
+                ),
+                unsafe { (*pac::UART0::ptr()).uartrsr.as_ptr() as u32 },
+                unsafe { (*pac::UART0::ptr()).uartcr.as_ptr() as u32 },
+            ),
+            #[cfg(feature = "uart1")]
+            console1: Console::new(
+                uart1,
+                clocks.peripheral_clock.freq(),
+                DmaRx::new(
+                    UART1_RX_CHANNEL,
+                    cortex_m::singleton!(: RxRing = RxRing::new()).unwrap(),
+                    unsafe { (*pac::UART1::ptr()).uartdr.as_ptr() as u32 },
+                    bsp::hal::dma::DREQ_UART1_RX,
+                ),
+                unsafe { (*pac::UART1::ptr()).uartrsr.as_ptr() as u32 },
+                unsafe { (*pac::UART1::ptr()).uartcr.as_ptr() as u32 },
+            ),
+            // Valves 0-7 on GPIO6-GPIO13, targets 8 and 9 unassigned.
+            valves: Valves::new([
+                Some(pins.gpio6.into()),
+                Some(pins.gpio7.into()),
+                Some(pins.gpio8.into()),
+                Some(pins.gpio9.into()),
+                Some(pins.gpio10.into()),
+                Some(pins.gpio11.into()),
+                Some(pins.gpio12.into()),
+                Some(pins.gpio13.into()),
+                None,
+                None,
+            ]),
+            gpio,
+            pwm: Pwm::new(Slices::new(pac.PWM, &mut pac.RESETS)),
+            // I2C1 SDA on GPIO14, SCL on GPIO15.
+            i2c: I2cBus::new(I2C::i2c1(
+                pac.I2C1,
+                pins.gpio14.into_mode::<FunctionI2C>(),
+                pins.gpio15.into_mode::<FunctionI2C>(),
+                100.kHz(),
+                &mut pac.RESETS,
+                clocks.system_clock.freq(),
+            )),
+            // SPI0 reserves its pins from the spare GPIOs as its first transfer starts.
+            spi: SpiBus::new(
+                Spi::new(pac.SPI0),
+                &mut pac.RESETS,
+                clocks.peripheral_clock.freq(),
+            ),
+            // Neopixel data on GPIO22.
+            pixels: Pixels::new(
+                pac.PIO0,
+                pins.gpio22.into_mode::<FunctionPio0>(),
+                &mut pac.RESETS,
+            ),
+            history: HistoryBuffer::new(),
+            pending: Vec::new(),
+            debounce: Debounce::new(),
+            watched: 0,
+            watched_levels: 0,
+            // The framed binary protocol suits noisy links, ASCII stays the default.
+            protocol: if cfg!(feature = "binary-protocol") {
+                Protocol::Binary
+            } else {
+                Protocol::Ascii
+            },
+            bridge: None,
+            reply: Transport::Usb,
+            verbose: false,
+            echo: false,
+            codes: false,
+            quiet: false,
+            numbered: false,
+            sequence: None,
+            framing: None,
+            request: None,
+            mid_line: false,
+            tenths: 0,
+            decoder_states: ["command"; TRANSPORT_COUNT],
+            status: Status::Ok,
+            failsafe: false,
+            estopped: false,
+            estop_rate: DEFAULT_RATE,
+            safe_valves: 0,
+            min_switch: [0; VALVE_COUNT],
+            last_switch: [None; VALVE_COUNT],
+            was_open: 0,
+            deadman: 0,
+            last_command: timer.get_counter(),
+            heartbeat: 0,
+            last_heartbeat: timer.get_counter(),
+            boot: timer.get_counter(),
+            id,
+            frequencies,
+            timer,
+        };
+        let mut counter = Counter::new();
+        if let Some(config) = Config::load() {
+            let shared = Unshared {
+                usb: &mut usb,
+                leds: &mut leds,
+                counter: &mut counter,
+            };
+            restore(
+                &mut Io {
+                    shared,
+                    state: &mut state,
+                },
+                &config,
+            );
+        }
         (
-            // UART TX (characters sent from RP2040) on pin 1 (GPIO0)
-            pins.gpio0.into_mode::<FunctionUart>(),
-            // UART RX (characters received by RP2040) on pin 2 (GPIO1)
-            pins.gpio1.into_mode::<FunctionUart>(),
-        ),
-        &mut pac.RESETS,
-    );
+            Shared { usb, leds, counter },
+            Local {
+                state,
+                inputs: Inputs::new(),
+                alarm,
+            },
+            init::Monotonics(),
+        )
+    }
 
-    let io = Io {
-        timer: Timer::new(pac.TIMER, &mut pac.RESETS),
-        led: Led::new(pins.led.into_push_pull_output()),
-        console: Console::new(uart, clocks.peripheral_clock.freq()),
-        usb: Usb::new(&usb_bus),
-    };
-    forever(io);
+    /// Commands, below everything else. Each run takes whatever input has
+    /// queued up since the last and returns, so the interrupt handlers run
+    /// whenever they need to.
+    #[task(priority = 1, capacity = 1, shared = [usb, leds, counter], local = [state, inputs])]
+    fn commands(cx: commands::Context) {
+        let mut io = Io {
+            shared: cx.shared,
+            state: cx.local.state,
+        };
+        run(&mut io, cx.local.inputs);
+    }
+
+    /// Service USB ahead of everything else, so input is never missed, then
+    /// have the command task take it.
+    #[task(binds = USBCTRL_IRQ, priority = 3, shared = [usb])]
+    fn usb_irq(mut cx: usb_irq::Context) {
+        if cx.shared.usb.lock(|usb| use_usb(usb, |usb| usb.poll())) {
+            // Already spawned does as well, that run takes this input too.
+            commands::spawn().ok();
+        }
+    }
+
+    #[task(binds = IO_IRQ_BANK0, priority = 3, shared = [counter])]
+    fn count_edge(mut cx: count_edge::Context) {
+        cx.shared.counter.lock(|counter| counter.on_interrupt());
+    }
+
+    /// Run the leds from the timer, so a slow command can't upset their
+    /// timing, and the command task at least once a tick for the UARTs and
+    /// the timeouts.
+    #[task(
+        binds = TIMER_IRQ_0,
+        priority = 2,
+        shared = [leds],
+        local = [alarm, next_run: Instant = Instant::from_ticks(0)]
+    )]
+    fn tick(mut cx: tick::Context) {
+        let alarm = cx.local.alarm;
+        alarm.clear_interrupt();
+        let now = now();
+        let dimmed = cx.shared.leds.lock(|leds| {
+            leds.run(&now);
+            leds.dimmed()
+        });
+        let period = if dimmed { DIMMED_TICK_US } else { TICK_US };
+        alarm.schedule(period.micros()).unwrap();
+        if now >= *cx.local.next_run {
+            *cx.local.next_run = now + TICK_US.micros();
+            // Already spawned does as well.
+            commands::spawn().ok();
+        }
+    }
+}
+
+/// The shared resources as `init` holds them, before any task can run.
+struct Unshared<'a> {
+    usb: &'a mut Usb<'static, HalUsbBus>,
+    leds: &'a mut LedBank,
+    counter: &'a mut Counter,
+}
+
+impl Shared for Unshared<'_> {
+    fn with_usb<R>(&mut self, f: impl FnOnce(&mut Usb<'static, HalUsbBus>) -> R) -> R {
+        use_usb(self.usb, f)
+    }
+
+    fn with_leds<R>(&mut self, f: impl FnOnce(&mut LedBank) -> R) -> R {
+        f(self.leds)
+    }
+
+    fn with_counter<R>(&mut self, f: impl FnOnce(&mut Counter) -> R) -> R {
+        f(self.counter)
+    }
+}
+
+impl Shared for app::commands::SharedResources<'_> {
+    fn with_usb<R>(&mut self, f: impl FnOnce(&mut Usb<'static, HalUsbBus>) -> R) -> R {
+        self.usb.lock(|usb| use_usb(usb, f))
+    }
+
+    fn with_leds<R>(&mut self, f: impl FnOnce(&mut LedBank) -> R) -> R {
+        self.leds.lock(f)
+    }
+
+    fn with_counter<R>(&mut self, f: impl FnOnce(&mut Counter) -> R) -> R {
+        self.counter.lock(f)
+    }
+}
+
+/// Bring up the crystal and PLLs, trying again in case a marginal crystal
+/// failed to start.
+fn init_clocks(
+    xosc: pac::XOSC,
+    clocks: pac::CLOCKS,
+    pll_sys: pac::PLL_SYS,
+    pll_usb: pac::PLL_USB,
+    resets: &mut pac::RESETS,
+    watchdog: &mut Watchdog,
+) -> Result<ClocksManager, InitError> {
+    let mut result = clocks::init_clocks_and_plls(
+        bsp::XOSC_CRYSTAL_FREQ,
+        xosc,
+        clocks,
+        pll_sys,
+        pll_usb,
+        resets,
+        watchdog,
+    );
+    for _ in 1..CLOCK_ATTEMPTS {
+        if result.is_ok() {
+            break;
+        }
+        // The failed attempt dropped the peripherals it was given, nothing
+        // else has them.
+        let pac = unsafe { pac::Peripherals::steal() };
+        result = clocks::init_clocks_and_plls(
+            bsp::XOSC_CRYSTAL_FREQ,
+            pac.XOSC,
+            pac.CLOCKS,
+            pac.PLL_SYS,
+            pac.PLL_USB,
+            resets,
+            watchdog,
+        );
+    }
+    result
 }
 
-fn forever<B: UsbBus, LP: PinId, D: UartDevice, P: ValidUartPinout<D>>(
-    mut io: Io<B, LP, D, P>,
+/// Blink the onboard led, long on and short off, for as long as the clocks
+/// won't start. Runs from the ring oscillator, so the timing is rough.
+fn clock_failure(
+    io_bank0: pac::IO_BANK0,
+    pads_bank0: pac::PADS_BANK0,
+    sio: pac::SIO,
+    resets: &mut pac::RESETS,
 ) -> ! {
-    let mut decoder = Decoder::new();
-    let mut usb_buffer = [0u8; 64];
-    let mut uart_buffer = [0u8; 16];
+    let sio = Sio::new(sio);
+    let pins = Pins::new(io_bank0, pads_bank0, sio.gpio_bank0, resets);
+    let mut led = pins.led.into_push_pull_output();
     loop {
-        let now = io.timer.get_counter();
-        io.led.run(&now);
-        if let Some(count) = io.usb.read(&mut usb_buffer) {
-            // Decode the input
-            for c in usb_buffer.iter().take(count) {
-                match decoder.run(c) {
-                    DecodeResult::None => {}
-                    DecodeResult::Text(text) => {
-                        if text.len() > 0 {
-                            io.usb.write(&text);
-                        }
-                    }
-                    DecodeResult::Command(cmd, target, value) => {
-                        if let Some(text) = command(&mut io, cmd, target, value) {
-                            io.usb.write(&text);
-                        }
-                    }
+        led.set_high().unwrap();
+        cortex_m::asm::delay(3 * FAILURE_BLINK_CYCLES);
+        led.set_low().unwrap();
+        cortex_m::asm::delay(FAILURE_BLINK_CYCLES);
+    }
+}
+
+/// The timer count, for tasks that can't reach the `Timer` in `Io`. Read the
+/// same way `Timer::get_counter` does, safe alongside it.
+fn now() -> Instant {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    loop {
+        let high = timer.timerawh.read().bits();
+        let low = timer.timerawl.read().bits();
+        if timer.timerawh.read().bits() == high {
+            return Instant::from_ticks((high as u64) << 32 | low as u64);
+        }
+    }
+}
+
+/// Input part way through decoding, kept between runs of the command task.
+struct Inputs {
+    decoder: Decoder,
+    /// Commands arriving over the UART are decoded separately.
+    uart_decoder: Decoder,
+    #[cfg(feature = "uart1")]
+    uart1_decoder: Decoder,
+    binary: BinaryDecoder,
+    bridge: Bridge,
+}
+
+impl Inputs {
+    fn new() -> Inputs {
+        Inputs {
+            decoder: new_decoder(),
+            uart_decoder: new_decoder(),
+            #[cfg(feature = "uart1")]
+            uart1_decoder: new_decoder(),
+            binary: BinaryDecoder::new(),
+            bridge: Bridge::new(),
+        }
+    }
+}
+
+fn new_decoder() -> Decoder {
+    match TERMINATOR {
+        Some(terminator) => Decoder::new_with_terminator(terminator),
+        None => Decoder::new(),
+    }
+}
+
+/// Take the input queued up since the last run and respond to it, then see
+/// to the timeouts and anything else due.
+fn run<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    inputs: &mut Inputs,
+) {
+    let Inputs {
+        decoder,
+        uart_decoder,
+        #[cfg(feature = "uart1")]
+        uart1_decoder,
+        binary,
+        bridge,
+    } = inputs;
+    let mut uart_buffer = [0u8; UART_BUFFER_SIZE];
+    let mut line = [0u8; usb::LINE_SIZE];
+    io.watchdog.feed();
+    io.flush_pending();
+    let now = io.timer.get_counter();
+    io.valves.run(&now);
+    for pin in 0..GPIO_COUNT as u8 {
+        if io.debounce.window(pin) > 0 {
+            if let Some(level) = io.gpio.read(pin) {
+                io.debounce.update(pin, level, now);
+            }
+        }
+    }
+    report_changes(io);
+    match io.with_usb(|usb| usb.connection_edge()) {
+        Some(true) if !io.quiet => banner(io),
+        // Nothing is watching the valves any more, reconnecting leaves
+        // them as they are.
+        // An emergency stop keeps everything closed regardless.
+        Some(false) if io.failsafe && !io.estopped => {
+            set_valves(io, io.safe_valves);
+        }
+        _ => {}
+    }
+    // Skipped while output is backed up, rather than adding to it.
+    if io.heartbeat > 0
+        && (now - io.last_heartbeat).to_millis() >= io.heartbeat
+        && io.pending.is_empty()
+    {
+        io.last_heartbeat = now;
+        respond(
+            io,
+            Transport::Usb,
+            DecodeResult::Command(Commands::Status, 0, 0),
+        );
+    }
+    // The host is connected but has stopped sending commands.
+    if io.deadman > 0 && (now - io.last_command).to_millis() >= io.deadman {
+        io.last_command = now;
+        set_valves(io, 0);
+    }
+    note_switches(io);
+    decoder.echo = io.echo;
+    uart_decoder.echo = io.echo;
+    decoder.verbose = io.verbose;
+    uart_decoder.verbose = io.verbose;
+    let result = decoder.tick(now);
+    io.decoder_states[Transport::Usb as usize] = decoder.state_name();
+    decoded(io, Transport::Usb, result);
+    let result = uart_decoder.tick(now);
+    io.decoder_states[Transport::Uart as usize] = uart_decoder.state_name();
+    decoded(io, Transport::Uart, result);
+    #[cfg(feature = "uart1")]
+    {
+        uart1_decoder.echo = io.echo;
+        uart1_decoder.verbose = io.verbose;
+        let result = uart1_decoder.tick(now);
+        io.decoder_states[Transport::Uart1 as usize] = uart1_decoder.state_name();
+        decoded(io, Transport::Uart1, result);
+    }
+    if cfg!(feature = "line-input") && io.protocol == Protocol::Ascii && io.bridge.is_none() {
+        while let Some(count) = io.with_usb(|usb| usb.read_line(&mut line)) {
+            for c in &line[..count] {
+                feed(io, decoder, Transport::Usb, c);
+            }
+        }
+    } else {
+        // Decode the input, bridged input the UART can't take yet stays queued.
+        while !io.bridge_full() {
+            let c = match io.with_usb(|usb| usb.dequeue()) {
+                Some(c) => c,
+                None => break,
+            };
+            if io.bridge.is_some() {
+                io.bridge_write(c);
+                if bridge.exit(c) {
+                    io.bridge = None;
+                    let mut text: String<64> = String::new();
+                    putln!(text, "BRIDGE0\r");
+                    io.usb_write(text.as_bytes());
+                }
+                continue;
+            }
+            match io.protocol {
+                Protocol::Ascii => feed(io, decoder, Transport::Usb, &c),
+                Protocol::Binary => {
+                    let result = binary.run(&c);
+                    handle(io, Transport::Usb, result);
                 }
             }
         }
-        match io.console.read(&mut uart_buffer) {
-            None => {}
-            Some(0) => {}
-            // Echo the input for now.
-            Some(_count) => {
-                io.console.write(&uart_buffer);
+    }
+    match io.console.read(&mut uart_buffer) {
+        None => {}
+        Some(0) => {}
+        Some(count) if io.bridge == Some(Transport::Uart) => {
+            io.usb_write(&uart_buffer[..count]);
+        }
+        Some(count) => {
+            for c in &uart_buffer[..count] {
+                feed(io, uart_decoder, Transport::Uart, c);
+            }
+        }
+    }
+    #[cfg(feature = "uart1")]
+    match io.console1.read(&mut uart_buffer) {
+        None => {}
+        Some(0) => {}
+        Some(count) if io.bridge == Some(Transport::Uart1) => {
+            io.usb_write(&uart_buffer[..count]);
+        }
+        Some(count) => {
+            for c in &uart_buffer[..count] {
+                feed(io, uart1_decoder, Transport::Uart1, c);
             }
         }
     }
 }
 
-fn command<B: UsbBus, LP: PinId, D: UartDevice, P: ValidUartPinout<D>>(
-    io: &mut Io<B, LP, D, P>,
+/// Decode a byte of ASCII input and respond, running a recalled command again.
+fn feed<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    decoder: &mut Decoder,
+    from: Transport,
+    c: &u8,
+) {
+    let result = decoder.run(c);
+    io.decoder_states[from as usize] = decoder.state_name();
+    if let DecodeResult::Recall(typed) = result {
+        // What was recorded never holds an up arrow, so this goes no deeper.
+        for c in typed.iter() {
+            feed(io, decoder, from, c);
+        }
+        return;
+    }
+    if matches!(result, DecodeResult::None | DecodeResult::Echo(_)) {
+        return decoded(io, from, result);
+    }
+    // Show the byte that completed the command too, ahead of the line break.
+    if io.echo && *c >= b' ' {
+        respond(io, from, DecodeResult::Echo(*c));
+    }
+    io.sequence = decoder.take_sequence();
+    io.tenths = decoder.take_tenths();
+    decoded(io, from, result);
+}
+
+/// Respond to decoder output, starting a new line after echoed input.
+fn decoded<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    from: Transport,
+    result: DecodeResult,
+) {
+    if io.echo && !matches!(result, DecodeResult::None | DecodeResult::Echo(_)) {
+        io.reply = from;
+        let mut text: String<64> = String::new();
+        putln!(text, "\r");
+        io.send(&text);
+    }
+    handle(io, from, result);
+}
+
+/// Respond to input from the host, a command that succeeds holds off the
+/// dead-man's switch.
+fn handle<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    from: Transport,
+    result: DecodeResult,
+) {
+    let request = match result {
+        DecodeResult::Command(cmd, target, value) => Some((cmd, Request::Set(target, value))),
+        DecodeResult::Query(cmd, target) => Some((cmd, Request::Query(target))),
+        DecodeResult::System(_) => Some((Commands::System, Request::Line)),
+        DecodeResult::Line(cmd, _) => Some((cmd, Request::Line)),
+        _ => None,
+    };
+    respond(io, from, result);
+    io.tenths = 0;
+    note_switches(io);
+    if let Some((cmd, request)) = request {
+        if io.status == Status::Ok {
+            let at = io.timer.get_counter();
+            io.last_command = at;
+            io.history.write(Accepted { at, cmd, request });
+        }
+    }
+}
+
+fn respond<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    from: Transport,
+    result: DecodeResult,
+) {
+    io.reply = from;
+    io.status = Status::Ok;
+    if let DecodeResult::Echo(c) = result {
+        let mut text: String<64> = String::new();
+        match c {
+            b'\r' => putln!(text, "\r"),
+            // Already ended the line at the carriage return.
+            b'\n' => {}
+            // Rub out the last character.
+            8 | 127 => put!(text, "\x08 \x08"),
+            0..=31 => put!(text, "^{}", (c + b'@') as char),
+            _ => put!(text, "{}", c as char),
+        }
+        io.send(&text);
+        return;
+    }
+    // Every line sent from here on is framed, not just the last.
+    io.framing = Some(io.sequence.take().unwrap_or(0));
+    io.mid_line = false;
+    match &result {
+        DecodeResult::Command(cmd, target, value) => describe(
+            io,
+            format_args!("{} target {} value {}", cmd, target, value),
+        ),
+        DecodeResult::Query(cmd, target) => {
+            describe(io, format_args!("{} target {} query", cmd, target))
+        }
+        DecodeResult::System(line) => describe(io, format_args!("!{}", line)),
+        DecodeResult::Line(cmd, line) => describe(io, format_args!("{} {}", cmd, line)),
+        _ => {}
+    }
+    // The leds answer their own commands and queries.
+    let led = io.with_leds(|leds| leds.respond(&result));
+    let text = match (led, result) {
+        (Some((text, status)), _) => {
+            io.status = status;
+            Some(text)
+        }
+        // Only the caller holding the decoder can replay a command.
+        (None, DecodeResult::None | DecodeResult::Recall(_) | DecodeResult::Echo(_)) => None,
+        (None, DecodeResult::Error(status, text)) => {
+            io.status = status;
+            Some(text)
+        }
+        (None, DecodeResult::Command(cmd, target, value)) => command(io, cmd, target, value),
+        (None, DecodeResult::Query(cmd, target)) => query(io, cmd, target),
+        (None, DecodeResult::System(line)) => system(io, &line),
+        (None, DecodeResult::Line(cmd, line)) => line_command(io, cmd, &line),
+    };
+    if let Some(text) = text {
+        io.send(&text);
+    }
+    io.framing = None;
+    io.request = None;
+}
+
+/// Note the request being answered, to lead the first line of its response
+/// in verbose mode.
+fn describe<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    request: fmt::Arguments,
+) {
+    let mut text: String<64> = String::new();
+    put!(text, "{}", request);
+    io.request = Some(text);
+}
+
+/// Terse error for a command with nothing to handle it.
+fn unhandled<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    cmd: Commands,
+    target: u8,
+) -> Option<String<64>> {
+    io.status = Status::UnknownCommand;
+    let mut text: String<64> = String::new();
+    putln!(
+        text,
+        "{}E{}\r",
+        cmd.letter().to_ascii_uppercase() as char,
+        target
+    );
+    Some(text)
+}
+
+fn command<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
     cmd: Commands,
     target: u8,
     value: u16,
 ) -> Option<String<64>> {
     let mut text: String<64> = String::new();
-    if cmd == Commands::Led {
-        io.led.rate = value as u64;
-        writeln!(text, "LA\r").unwrap();
+    if io.estopped
+        && matches!(
+            cmd,
+            Commands::Valve
+                | Commands::ValveAll
+                | Commands::ValvePulse
+                | Commands::Pwm
+                | Commands::Servo
+        )
+    {
+        io.status = Status::Stopped;
+        putln!(text, "ESTOPPED\r");
         Some(text)
     } else if cmd == Commands::Status {
-        writeln!(text, "SLv{}r{}\r", io.led.is_on() as i32, io.led.rate).unwrap();
+        let uptime = (io.timer.get_counter() - io.boot).to_millis();
+        let status = io.with_leds(|leds| leds.onboard().status());
+        putln!(text, "S{}u{}\r", status, uptime);
         Some(text)
-    } else {
-        writeln!(
+    } else if cmd == Commands::StatusJson {
+        // Too long for one response, so send it in pieces.
+        let uptime = (io.timer.get_counter() - io.boot).to_millis();
+        let status = io.with_leds(|leds| leds.onboard().status());
+        put!(
             text,
-            "run_command(command: '{}' target: {} value: {})\r",
-            cmd, target, value
-        )
-        .unwrap();
+            "{{led:{},rate:{},mode:{},",
+            status.on as i32,
+            status.rate,
+            status.mode
+        );
+        io.send(&text);
+        text.clear();
+        put!(text, "valves:[");
+        for target in 0..VALVE_COUNT as u8 {
+            let separator = if target == 0 { "" } else { "," };
+            let on = io.valves.is_on(target) == Some(true);
+            put!(text, "{}{}", separator, on as i32);
+        }
+        put!(text, "],");
+        io.send(&text);
+        text.clear();
+        putln!(
+            text,
+            "temp:{},vsys:{},uptime:{}}}\r",
+            io.analog.temperature(),
+            io.analog.vsys(),
+            uptime
+        );
         Some(text)
-    }
-}
-
-// End of file
+    } else if cmd == Commands::Version {
+        putln!(
+            text,
+            "FW{}-{}\r",
+            env!("CARGO_PKG_VERSION"),
+            env!("GIT_HASH")
+        );
+        Some(text)
+    } else if cmd == Commands::Id {
+        putln!(text, "ID{:016X}\r", io.id);
+        Some(text)
+    } else if cmd == Commands::I2c {
+        match io.i2c.scan() {
+            Some(found) => {
+                put!(text, "I");
+                for (n, address) in found.iter().enumerate() {
+                    // Room is left for the line ending, a crowded bus is cut short.
+                    if text.len() + 5 > text.capacity() {
+                        break;
+                    }
+                    let separator = if n == 0 { "" } else { "," };
+                    put!(text, "{}{:02X}", separator, address);
+                }
+                putln!(text, "\r");
+            }
+            None => {
+                io.status = Status::NoResponse;
+                putln!(text, "IE\r")
+            }
+        }
+        Some(text)
+    } else if cmd == Commands::Temperature {
+        putln!(text, "T{}\r", io.analog.temperature());
+        Some(text)
+    } else if cmd == Commands::Input {
+        // A reserved pin reads as None, whatever debounce last saw.
+        let level = io.gpio.read(target);
+        match level.map(|raw| io.debounce.level(target).unwrap_or(raw)) {
+            Some(high) => putln!(text, "G{}{}\r", target, high as i32),
+            None => {
+                io.status = Status::BadTarget;
+                putln!(text, "GE{}\r", target)
+            }
+        }
+        Some(text)
+    } else if cmd == Commands::OneWire {
+        match io.state.gpio.pin_mut(target) {
+            Some(pin) => match onewire::read_temperature(pin, &io.state.timer) {
+                Some(milli_c) => putln!(text, "1W{}\r", milli_c),
+                None => {
+                    io.status = Status::NoResponse;
+                    putln!(text, "1WE{}\r", target)
+                }
+            },
+            None => {
+                io.status = Status::BadTarget;
+                putln!(text, "1WE{}\r", target)
+            }
+        }
+        Some(text)
+    } else if cmd == Commands::Count {
+        // Reading leaves the pin an input with its bias.
+        if io.gpio.read(target).is_some() {
+            io.shared.with_counter(|counter| counter.enable(target));
+            io.debounce.take_rises(target);
+            putln!(text, "CA{}\r", target);
+        } else {
+            io.status = Status::BadTarget;
+            putln!(text, "CE{}\r", target);
+        }
+        Some(text)
+    } else if cmd == Commands::CountRead {
+        if io.gpio.pin_mut(target).is_some() {
+            // A debounced pin counts its settled edges instead.
+            let count = io.shared.with_counter(|counter| counter.take(target));
+            let count = match io.debounce.window(target) {
+                0 => count,
+                _ => io.debounce.take_rises(target),
+            };
+            putln!(text, "C{}{}\r", target, count);
+        } else {
+            io.status = Status::BadTarget;
+            putln!(text, "CE{}\r", target);
+        }
+        Some(text)
+    } else if cmd == Commands::Output {
+        let high = value != 0;
+        if io.gpio.write(target, high) {
+            putln!(text, "W{}{}\r", target, high as i32);
+        } else {
+            io.status = Status::BadTarget;
+            putln!(text, "WE{}\r", target);
+        }
+        Some(text)
+    } else if cmd == Commands::Bias {
+        match Bias::from_code(value) {
+            Some(bias) if io.gpio.set_bias(target, bias) => {
+                putln!(text, "P{}{}\r", target, value)
+            }
+            bias => {
+                io.status = match bias {
+                    Some(_) => Status::BadTarget,
+                    None => Status::BadValue,
+                };
+                putln!(text, "PE{}\r", target)
+            }
+        }
+        Some(text)
+    } else if cmd == Commands::Analog {
+        match io.analog.millivolts(target) {
+            Some(mv) => putln!(text, "A{}{}\r", target, mv),
+            None => {
+                io.status = Status::BadTarget;
+                putln!(text, "AE{}\r", target)
+            }
+        }
+        Some(text)
+    } else if cmd == Commands::Pwm {
+        let duty = Tenths(value as u32 * 10 + io.tenths as u32);
+        // The pin is checked before its slice is touched, and only claimed
+        // once the slice can run at 1 kHz.
+        if duty.0 <= u8::MAX as u32 * 10
+            && io.gpio.pin_mut(target).is_some()
+            && io
+                .state
+                .pwm
+                .set_duty_tenths(target, duty.0 as u16, |pin| io.state.gpio.is_pwm(pin))
+            && io.gpio.claim_pwm(target)
+        {
+            putln!(text, "M{}{}\r", target, duty);
+        } else {
+            io.status = if duty.0 > u8::MAX as u32 * 10 {
+                Status::BadValue
+            } else {
+                Status::BadTarget
+            };
+            putln!(text, "ME{}\r", target);
+        }
+        Some(text)
+    } else if cmd == Commands::Servo {
+        let angle = (value as u32 * 10 + io.tenths as u32).min(SERVO_MAX_ANGLE as u32 * 10);
+        if io.gpio.pin_mut(target).is_some()
+            && io
+                .state
+                .pwm
+                .set_angle_tenths(target, angle as u16, |pin| io.state.gpio.is_pwm(pin))
+            && io.gpio.claim_pwm(target)
+        {
+            putln!(text, "O{}{}\r", target, Tenths(angle));
+        } else {
+            io.status = Status::BadTarget;
+            putln!(text, "OE{}\r", target);
+        }
+        Some(text)
+    } else if cmd == Commands::Help {
+        // Too long for one response, so send it a line at a time.
+        for command in Commands::all() {
+            for line in command.help() {
+                text.clear();
+                putln!(text, "{}\r", line);
+                io.send(&text);
+            }
+        }
+        None
+    } else if matches!(
+        cmd,
+        Commands::Valve | Commands::ValvePulse | Commands::ValveAll
+    ) && throttled(io, switching(io, cmd, target, value))
+    {
+        io.status = Status::Throttled;
+        if cmd == Commands::ValveAll {
+            putln!(text, "VT*\r");
+        } else {
+            putln!(text, "VT{}\r", target);
+        }
+        Some(text)
+    } else if cmd == Commands::ValveAll {
+        if set_valves(io, value) {
+            io.acknowledge();
+            putln!(text, "VA*{}\r", value);
+        } else {
+            io.status = Status::BadValue;
+            putln!(text, "VE*\r");
+        }
+        Some(text)
+    } else if cmd == Commands::ValvePulse {
+        let until = io.timer.get_counter() + (value as u32).millis();
+        if io.valves.pulse(target, until) {
+            io.acknowledge();
+            putln!(text, "VP{}{}\r", target, value);
+        } else {
+            io.status = Status::BadTarget;
+            putln!(text, "VE{}\r", target);
+        }
+        Some(text)
+    } else if cmd == Commands::Valve && io.valves.mode(target) == Some(ValveMode::Pwm) {
+        let level = match u8::try_from(value) {
+            Ok(duty) => io.valves.set_level(target, duty),
+            Err(_) => None,
+        };
+        match level {
+            Some((pin, duty)) => {
+                io.pwm.set_duty(pin, duty);
+                io.acknowledge();
+                putln!(text, "VA{}\r", target);
+            }
+            _ => {
+                io.status = Status::BadValue;
+                putln!(text, "VE{}\r", target);
+            }
+        }
+        Some(text)
+    } else if cmd == Commands::Valve {
+        if io.valves.set(target, value != 0) {
+            io.acknowledge();
+            putln!(text, "VA{}\r", target);
+        } else {
+            io.status = Status::BadTarget;
+            putln!(text, "VE{}\r", target);
+        }
+        Some(text)
+    } else {
+        unhandled(io, cmd, target)
+    }
+}
+
+/// Pulse the stepper STEP pin `count` times with `delay_us` between edges,
+/// after setting DIR. Blocks throughout, so the caller keeps the run short.
+/// Returns false without stepping if either pin is reserved.
+fn step<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    forward: bool,
+    count: u32,
+    delay_us: u32,
+) -> bool {
+    if !io.gpio.write(STEP_PIN, false) || !io.gpio.write(DIR_PIN, forward) {
+        return false;
+    }
+    // The DIR setup time before the first edge.
+    let mut next = io.timer.get_counter() + delay_us.micros();
+    for edge in 0..count * 2 {
+        while io.timer.get_counter() < next {}
+        io.gpio.write(STEP_PIN, edge.is_multiple_of(2));
+        next += delay_us.micros();
+    }
+    true
+}
+
+/// A value in tenths, shown with its decimal place only when it has one.
+struct Tenths(u32);
+
+impl fmt::Display for Tenths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 % 10 {
+            0 => write!(f, "{}", self.0 / 10),
+            tenths => write!(f, "{}.{}", self.0 / 10, tenths),
+        }
+    }
+}
+
+/// Result of one `!selftest` check.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Outcome {
+    Pass,
+    Fail,
+    /// Nothing to test against, such as no loopback jumper.
+    Skip,
+    /// Driven for someone to watch, with nothing to read back, such as the
+    /// onboard led.
+    Toggled,
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "pass"),
+            Outcome::Fail => write!(f, "fail"),
+            Outcome::Skip => write!(f, "skip"),
+            Outcome::Toggled => write!(f, "toggled"),
+        }
+    }
+}
+
+/// Check the temperature sensor reads sensibly and that bytes sent on the
+/// UART come back when TX is jumpered to RX. The onboard led is switched
+/// on then off for someone to watch, as its pin can't be read back.
+fn self_test<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+) -> Vec<(&'static str, Outcome), 3> {
+    let mut results = Vec::new();
+    let temperature = io.analog.temperature();
+    let outcome = if SELFTEST_TEMPERATURES.contains(&temperature) {
+        Outcome::Pass
+    } else {
+        Outcome::Fail
+    };
+    results.push(("temp", outcome)).ok();
+
+    // Held through the override so the led timer can't undo it meanwhile,
+    // then handed back to whatever override, if any, was there before.
+    let previous = io.with_leds(|leds| leds.onboard().manual_override);
+    for on in [true, false] {
+        let now = io.timer.get_counter();
+        io.with_leds(|leds| {
+            let led = leds.onboard();
+            led.manual_override = Some(on);
+            led.run(&now);
+        });
+        let until = now + SELFTEST_LED_MS.millis();
+        while io.timer.get_counter() < until {}
+    }
+    let now = io.timer.get_counter();
+    io.with_leds(|leds| {
+        let led = leds.onboard();
+        led.manual_override = previous;
+        led.run(&now);
+    });
+    results.push(("led", Outcome::Toggled)).ok();
+
+    // Anything already received would be taken for the echo.
+    let mut buffer = [0u8; UART_BUFFER_SIZE];
+    while io.console.read(&mut buffer).is_some() {}
+    io.state
+        .console
+        .write_all(SELFTEST_LOOPBACK, &io.state.timer, UART_WRITE_MS);
+    io.state.console.flush(&io.state.timer, UART_WRITE_MS);
+    let until = io.timer.get_counter() + SELFTEST_LOOPBACK_MS.millis();
+    while io.timer.get_counter() < until {}
+    let outcome = match io.console.read(&mut buffer) {
+        None => Outcome::Skip,
+        Some(count) if buffer[..count] == *SELFTEST_LOOPBACK => Outcome::Pass,
+        Some(_) => Outcome::Fail,
+    };
+    results.push(("uart", outcome)).ok();
+    results
+}
+
+/// Send `EV<pin><level>` for each watched pin that has changed, once any
+/// debounce has settled it.
+fn report_changes<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(io: &mut Io<'_, S, D, P>) {
+    for pin in 0..GPIO_COUNT as u8 {
+        let bit = 1 << pin;
+        if io.watched & bit == 0 {
+            continue;
+        }
+        let level = io.gpio.read(pin);
+        let level = match level.map(|raw| io.debounce.level(pin).unwrap_or(raw)) {
+            Some(level) => level,
+            None => continue,
+        };
+        if (io.watched_levels & bit != 0) != level {
+            io.watched_levels ^= bit;
+            let mut text: String<64> = String::new();
+            putln!(text, "EV{}{}\r", pin, level as i32);
+            io.usb_write(text.as_bytes());
+        }
+    }
+}
+
+/// Bit n set for each open valve n.
+fn open_valves<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(io: &Io<'_, S, D, P>) -> u16 {
+    (0..VALVE_COUNT as u8)
+        .filter(|target| io.valves.is_on(*target) == Some(true))
+        .fold(0, |mask, target| mask | 1 << target)
+}
+
+/// The valves a valve command would open or close, bit n for valve n.
+fn switching<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &Io<'_, S, D, P>,
+    cmd: Commands,
+    target: u8,
+    value: u16,
+) -> u16 {
+    let open = open_valves(io);
+    let bit = 1u16.checked_shl(target as u32).unwrap_or(0);
+    match cmd {
+        Commands::ValveAll => (open ^ value) & io.valves.assigned(),
+        Commands::ValvePulse => !open & bit,
+        _ => (open ^ if value != 0 { bit } else { 0 }) & bit,
+    }
+}
+
+/// True if any of the valves in `switching` last switched too recently.
+fn throttled<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &Io<'_, S, D, P>,
+    switching: u16,
+) -> bool {
+    let now = io.timer.get_counter();
+    (0..VALVE_COUNT).any(|target| {
+        let limit = io.min_switch[target] as u64;
+        match io.last_switch[target] {
+            Some(last) if switching & 1 << target != 0 && limit > 0 => {
+                (now - last).to_millis() < limit
+            }
+            _ => false,
+        }
+    })
+}
+
+/// Time stamp the valves that have switched since last noted, by command,
+/// pulse or safety.
+fn note_switches<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(io: &mut Io<'_, S, D, P>) {
+    let open = open_valves(io);
+    let changed = open ^ io.was_open;
+    if changed == 0 {
+        return;
+    }
+    let now = io.timer.get_counter();
+    for (target, last) in io.last_switch.iter_mut().enumerate() {
+        if changed & 1 << target != 0 {
+            *last = Some(now);
+        }
+    }
+    io.was_open = open;
+}
+
+/// Announce the firmware to a terminal as it opens the USB port, with the
+/// version and id as their commands give them.
+fn banner<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(io: &mut Io<'_, S, D, P>) {
+    respond(
+        io,
+        Transport::Usb,
+        DecodeResult::Command(Commands::Version, 0, 0),
+    );
+    respond(
+        io,
+        Transport::Usb,
+        DecodeResult::Command(Commands::Id, 0, 0),
+    );
+    let mut text: String<64> = String::new();
+    putln!(
+        text,
+        "{} ready, h for help, ! for settings\r",
+        env!("CARGO_PKG_NAME")
+    );
+    io.send(&text);
+}
+
+/// Close every valve and hold every PWM output low, latching until `!clear`
+/// with the onboard led blinking fast.
+fn estop<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(io: &mut Io<'_, S, D, P>) {
+    // Stopping again keeps the rate from before the first stop.
+    if !io.estopped {
+        io.estop_rate = io.with_leds(|leds| leds.onboard().rate);
+    }
+    io.estopped = true;
+    // Valves in PWM mode are driven closed again after, inverted ones need
+    // full duty for that.
+    io.pwm.stop();
+    set_valves(io, 0);
+    io.with_leds(|leds| leds.onboard().set_rate(ESTOP_BLINK_MS));
+}
+
+/// Open the valves whose bits are set in `mask` and close the rest, valves in
+/// PWM mode going to full or zero duty. Returns false, changing nothing, if
+/// a set bit is a target with no pin assigned.
+fn set_valves<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    mask: u16,
+) -> bool {
+    if !io.valves.set_all(mask) {
+        return false;
+    }
+    for target in 0..VALVE_COUNT as u8 {
+        let duty = if mask & 1 << target != 0 { u8::MAX } else { 0 };
+        if let Some((pin, duty)) = io.valves.set_level(target, duty) {
+            io.pwm.set_duty(pin, duty);
+        }
+    }
+    true
+}
+
+fn query<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    cmd: Commands,
+    target: u8,
+) -> Option<String<64>> {
+    let mut text: String<64> = String::new();
+    if let (Commands::Valve, Some(duty)) = (cmd, io.valves.level(target)) {
+        putln!(text, "VQ{}p{}\r", target, duty);
+        Some(text)
+    } else if cmd == Commands::Valve {
+        match io.valves.is_on(target) {
+            Some(on) => putln!(text, "VQ{}{}\r", target, on as i32),
+            None => {
+                io.status = Status::BadTarget;
+                putln!(text, "VE{}\r", target)
+            }
+        }
+        Some(text)
+    } else {
+        unhandled(io, cmd, target)
+    }
+}
+
+fn line_command<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    cmd: Commands,
+    line: &str,
+) -> Option<String<64>> {
+    let mut text: String<64> = String::new();
+    if cmd == Commands::I2c {
+        i2c_transfer(io, line)
+    } else if cmd == Commands::Pixel {
+        match pixel::parse(line) {
+            Some((index, rgb)) if io.pixels.set(index, rgb) => {
+                putln!(text, "N{}{:06X}\r", index, rgb)
+            }
+            _ => {
+                io.status = Status::BadValue;
+                putln!(text, "NE\r")
+            }
+        }
+        Some(text)
+    } else {
+        unhandled(io, cmd, 0)
+    }
+}
+
+/// `w<addr><bytes>` or `r<addr><len>`, all in hex with spaces ignored.
+fn i2c_transfer<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    line: &str,
+) -> Option<String<64>> {
+    let mut text: String<64> = String::new();
+    let mut chars = line.chars();
+    let op = chars.next();
+    let hex = chars.as_str();
+    let bytes: Option<Vec<u8, { MAX_TRANSFER + 1 }>> = parse_hex(hex.split(' '));
+    let mut buffer = [0u8; MAX_TRANSFER];
+    let result = match (op, bytes.as_deref()) {
+        (Some('w'), Some([address, payload @ ..])) => io.i2c.write(*address, payload),
+        (Some('r'), Some(&[address, len])) if (1..=MAX_TRANSFER).contains(&(len as usize)) => {
+            let buffer = &mut buffer[..len as usize];
+            io.i2c.read(address, buffer).map(|_| {
+                put!(text, "IR");
+                for byte in buffer.iter() {
+                    put!(text, "{:02X}", byte);
+                }
+            })
+        }
+        _ => Err(i2c::Error::Invalid),
+    };
+    match result {
+        Ok(()) if op == Some('r') => putln!(text, "\r"),
+        Ok(()) => putln!(text, "IWOK\r"),
+        Err(i2c::Error::Nak) => {
+            io.status = Status::NoResponse;
+            putln!(text, "INAK\r")
+        }
+        Err(error) => {
+            io.status = match error {
+                i2c::Error::Busy => Status::NoResponse,
+                _ => Status::BadValue,
+            };
+            putln!(text, "IE\r")
+        }
+    }
+    Some(text)
+}
+
+/// Send hex bytes over SPI with chip select held low, answering with the
+/// bytes received.
+fn spi_transfer<'a, S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    words: impl Iterator<Item = &'a str>,
+) -> Option<String<64>> {
+    let mut text: String<64> = String::new();
+    let bytes: Option<Vec<u8, { spi::MAX_TRANSFER }>> = parse_hex(words);
+    let pins = [spi::MISO_PIN, spi::SCK_PIN, spi::MOSI_PIN];
+    match bytes {
+        Some(mut bytes)
+            if !bytes.is_empty()
+                && pins.iter().all(|pin| io.gpio.claim_spi(*pin))
+                && io.gpio.claim_output(spi::CS_PIN)
+                && io.gpio.drive(spi::CS_PIN, false) =>
+        {
+            io.spi.transfer(&mut bytes);
+            io.gpio.drive(spi::CS_PIN, true);
+            put!(text, "SPI");
+            for byte in bytes.iter() {
+                put!(text, "{:02X}", byte);
+            }
+            putln!(text, "\r");
+        }
+        _ => {
+            io.status = Status::BadValue;
+            putln!(text, "SPIE\r")
+        }
+    }
+    Some(text)
+}
+
+/// A decimal number, or hex after `0x`.
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// CRC-32 of `len` bytes of flash from `start`, which must be in range, taken
+/// in pieces with the watchdog fed between them however long it is.
+fn crc_flash<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    start: u32,
+    len: u32,
+) -> u32 {
+    let mut crc = !0;
+    let end = start + len;
+    let mut offset = start;
+    while offset < end {
+        let chunk = CRC_CHUNK.min(end - offset);
+        crc = flash::crc32(crc, offset, chunk).unwrap();
+        io.watchdog.feed();
+        offset += chunk;
+    }
+    crc
+}
+
+/// Pairs of hex digits as bytes, run together across the words they are
+/// split into. None if any are malformed or they don't fit.
+fn parse_hex<'a, const N: usize>(words: impl Iterator<Item = &'a str>) -> Option<Vec<u8, N>> {
+    let digits: Vec<u8, 64> = words.flat_map(str::bytes).collect();
+    if !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    let mut bytes = Vec::new();
+    for pair in digits.chunks(2) {
+        let byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+        bytes.push(byte).ok()?;
+    }
+    Some(bytes)
+}
+
+fn system<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    line: &str,
+) -> Option<String<64>> {
+    let mut text: String<64> = String::new();
+    let mut words = line.split_whitespace();
+    match words.next() {
+        None => None,
+        Some("boot") => {
+            putln!(text, "BOOT\r");
+            io.send(&text);
+            drain(io);
+            rom_data::reset_to_usb_boot(0, 0);
+            None
+        }
+        Some("reset") => {
+            putln!(text, "RST\r");
+            io.send(&text);
+            drain(io);
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        Some("nowatchdog") => {
+            io.watchdog.disable();
+            putln!(text, "WD0\r");
+            Some(text)
+        }
+        Some("verbose") => {
+            match words.next() {
+                Some(flag @ ("0" | "1")) => {
+                    io.verbose = flag == "1";
+                    putln!(text, "VERBOSE{}\r", flag)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("echo") => {
+            // A lone 0 or 1 switches echoing, anything else comes straight
+            // back as a probe of the link.
+            let rest = &line.trim_start()["echo".len()..];
+            let rest = rest.strip_prefix(' ').unwrap_or(rest);
+            match rest.trim_end() {
+                flag @ ("0" | "1") => {
+                    io.echo = flag == "1";
+                    putln!(text, "ECHO{}\r", flag)
+                }
+                "" => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+                _ => {
+                    // The decoder ends the line at any other control code.
+                    for c in rest.chars() {
+                        match c {
+                            '\x7f' => put!(text, "^?"),
+                            _ => put!(text, "{}", c),
+                        }
+                    }
+                    putln!(text, "\r")
+                }
+            }
+            Some(text)
+        }
+        Some("codes") => {
+            match words.next() {
+                Some(flag @ ("0" | "1")) => {
+                    io.codes = flag == "1";
+                    putln!(text, "CODES{}\r", flag)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("quiet") => {
+            match words.next() {
+                Some(flag @ ("0" | "1")) => {
+                    io.quiet = flag == "1";
+                    putln!(text, "QUIET{}\r", flag)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("seq") => {
+            match words.next() {
+                Some(flag @ ("0" | "1")) => {
+                    io.numbered = flag == "1";
+                    putln!(text, "SEQ{}\r", flag)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("failsafe") => {
+            match words.next() {
+                Some(flag @ ("0" | "1")) => {
+                    io.failsafe = flag == "1";
+                    putln!(text, "FAILSAFE{}\r", flag)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("minswitch") => {
+            let target = words.next().and_then(|target| target.parse::<u8>().ok());
+            let ms = words.next().and_then(|ms| ms.parse::<u32>().ok());
+            match (target, ms) {
+                (Some(target), Some(ms)) if io.valves.mode(target).is_some() => {
+                    io.min_switch[target as usize] = ms;
+                    putln!(text, "MINSWITCH{} {}\r", target, ms)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad valve or interval\r")
+                }
+            }
+            Some(text)
+        }
+        Some("valveinvert") => {
+            let target = words.next().and_then(|target| target.parse::<u8>().ok());
+            match (target, words.next()) {
+                (Some(target), Some(flag @ ("0" | "1")))
+                    if io.valves.set_inverted(target, flag == "1") =>
+                {
+                    if let Some((pin, duty)) = io.valves.set_level(target, 0) {
+                        io.pwm.set_duty(pin, duty);
+                    }
+                    putln!(text, "VINV{}{}\r", target, flag)
+                }
+                (Some(target), None) if io.valves.mode(target).is_some() => {
+                    let inverted = io.valves.inverted() & 1 << target != 0;
+                    putln!(text, "VINV{}{}\r", target, inverted as i32)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad valve or flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("valvestats") => {
+            match words.next() {
+                None => {
+                    // Too long for one response with large counts, so sent
+                    // in pieces as it fills.
+                    put!(text, "VS ");
+                    for (target, count) in io.valves.actuations().iter().enumerate() {
+                        if text.len() + 12 > text.capacity() {
+                            io.send(&text);
+                            text.clear();
+                        }
+                        let separator = if target == 0 { "" } else { "," };
+                        put!(text, "{}{}", separator, count);
+                    }
+                    putln!(text, "\r")
+                }
+                Some("reset") => {
+                    io.valves.set_actuations([0; VALVE_COUNT]);
+                    putln!(text, "VSRESET\r")
+                }
+                Some(_) => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad option\r")
+                }
+            }
+            Some(text)
+        }
+        Some("valvemode") => {
+            let target = words.next().and_then(|target| target.parse::<u8>().ok());
+            let mode = words.next().map(ValveMode::parse);
+            match (target.and_then(|target| io.valves.mode(target)), mode) {
+                (Some(current), None) => {
+                    putln!(text, "VMODE{} {}\r", target.unwrap(), current)
+                }
+                (Some(_), Some(Some(mode))) => {
+                    let target = target.unwrap();
+                    io.valves.set_mode(target, mode);
+                    if let Some((pin, duty)) = io.valves.set_level(target, 0) {
+                        io.pwm.set_duty(pin, duty);
+                    }
+                    putln!(text, "VMODE{} {}\r", target, mode)
+                }
+                (None, _) => {
+                    io.status = Status::BadTarget;
+                    putln!(text, "Err: bad valve\r")
+                }
+                (Some(_), Some(None)) => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad mode\r")
+                }
+            }
+            Some(text)
+        }
+        Some("estop") => {
+            estop(io);
+            putln!(text, "ESTOP\r");
+            Some(text)
+        }
+        Some("clear") => {
+            // The valves stay closed, only commands are accepted again.
+            if io.estopped {
+                io.estopped = false;
+                // Unless the led was set to something else meanwhile.
+                let rate = io.estop_rate;
+                io.with_leds(|leds| {
+                    let led = leds.onboard();
+                    if led.rate == ESTOP_BLINK_MS {
+                        led.set_rate(rate);
+                    }
+                });
+            }
+            putln!(text, "CLEAR\r");
+            Some(text)
+        }
+        Some("safe") => {
+            match words.next().and_then(|mask| mask.parse::<u16>().ok()) {
+                Some(mask) if mask & !io.valves.assigned() == 0 => {
+                    io.safe_valves = mask;
+                    putln!(text, "SAFE{}\r", mask)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad mask\r")
+                }
+            }
+            Some(text)
+        }
+        Some("bridge") => {
+            io.bridge = Some(Transport::Uart);
+            putln!(text, "BRIDGE1\r");
+            Some(text)
+        }
+        #[cfg(feature = "uart1")]
+        Some("bridge1") => {
+            io.bridge = Some(Transport::Uart1);
+            putln!(text, "BRIDGE1 UART1\r");
+            Some(text)
+        }
+        Some("heartbeat") => {
+            match words.next().and_then(|ms| ms.parse::<u64>().ok()) {
+                Some(ms) => {
+                    io.heartbeat = ms;
+                    io.last_heartbeat = io.timer.get_counter();
+                    putln!(text, "HB{}\r", ms)
+                }
+                None => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad interval\r")
+                }
+            }
+            Some(text)
+        }
+        Some("spi") => spi_transfer(io, words),
+        Some("spicfg") => {
+            let mode = words.next().and_then(|mode| mode.parse::<u8>().ok());
+            let baud = words.next().and_then(|baud| baud.parse::<u32>().ok());
+            match (mode, baud) {
+                (None, None) => putln!(text, "SPICFG{} {}\r", io.spi.mode(), io.spi.baud()),
+                (Some(mode), Some(baud)) if io.spi.configure(mode, baud) => {
+                    putln!(text, "SPICFG{} {}\r", mode, io.spi.baud())
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad spi config\r")
+                }
+            }
+            Some(text)
+        }
+        Some("hist") => {
+            // A line each, oldest first.
+            let history: Vec<Accepted, HISTORY_SIZE> =
+                io.history.oldest_ordered().copied().collect();
+            for accepted in history.iter() {
+                text.clear();
+                put!(
+                    text,
+                    "H{} {}",
+                    (accepted.at - io.boot).to_millis(),
+                    accepted.cmd
+                );
+                match accepted.request {
+                    Request::Set(target, value) => putln!(text, " {} {}\r", target, value),
+                    Request::Query(target) => putln!(text, " {} ?\r", target),
+                    Request::Line => putln!(text, "\r"),
+                }
+                io.send(&text);
+            }
+            text.clear();
+            putln!(text, "HIST{}\r", io.history.len());
+            Some(text)
+        }
+        Some("selftest") => {
+            let results = self_test(io);
+            for (check, outcome) in results.iter() {
+                text.clear();
+                putln!(text, "TEST {} {}\r", check, outcome);
+                io.send(&text);
+            }
+            text.clear();
+            if results.iter().any(|(_, outcome)| *outcome == Outcome::Fail) {
+                io.status = Status::NoResponse;
+                putln!(text, "SELFTEST fail\r")
+            } else {
+                putln!(text, "SELFTEST pass\r")
+            }
+            Some(text)
+        }
+        Some("step") => {
+            let dir = words.next();
+            let count = words.next().and_then(|count| count.parse::<u32>().ok());
+            let delay_us = words.next().and_then(|us| us.parse::<u32>().ok());
+            match (dir, count, delay_us) {
+                (Some(dir @ ("0" | "1")), Some(count), Some(delay_us))
+                    if delay_us > 0 && (2 * count as u64 + 1) * delay_us as u64 <= STEP_MAX_US =>
+                {
+                    if step(io, dir == "1", count, delay_us) {
+                        putln!(text, "STEP{}\r", count)
+                    } else {
+                        io.status = Status::BadTarget;
+                        putln!(text, "Err: step pins in use\r")
+                    }
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad step\r")
+                }
+            }
+            Some(text)
+        }
+        Some("watch") => {
+            let pin = words.next().and_then(|pin| pin.parse::<u8>().ok());
+            match pin.and_then(|pin| Some((pin, io.gpio.read(pin)?))) {
+                Some((pin, level)) => {
+                    let level = io.debounce.level(pin).unwrap_or(level);
+                    io.watched |= 1 << pin;
+                    // Changes are reported from the level as it is now.
+                    io.watched_levels = io.watched_levels & !(1 << pin) | (level as u32) << pin;
+                    putln!(text, "WATCH{} {}\r", pin, level as i32)
+                }
+                None => {
+                    io.status = Status::BadTarget;
+                    putln!(text, "Err: bad pin\r")
+                }
+            }
+            Some(text)
+        }
+        Some("unwatch") => {
+            match words.next().and_then(|pin| pin.parse::<u8>().ok()) {
+                Some(pin) if (pin as usize) < GPIO_COUNT => {
+                    io.watched &= !(1 << pin);
+                    putln!(text, "UNWATCH{}\r", pin)
+                }
+                _ => {
+                    io.status = Status::BadTarget;
+                    putln!(text, "Err: bad pin\r")
+                }
+            }
+            Some(text)
+        }
+        Some("debounce") => {
+            let pin = words.next().and_then(|pin| pin.parse::<u8>().ok());
+            let ms = words.next().and_then(|ms| ms.parse::<u16>().ok());
+            match (pin.and_then(|pin| io.gpio.read(pin)), ms) {
+                (Some(level), Some(ms)) => {
+                    let pin = pin.unwrap();
+                    io.debounce.set(pin, ms, level);
+                    putln!(text, "DEBOUNCE{} {}\r", pin, ms)
+                }
+                (None, _) => {
+                    io.status = Status::BadTarget;
+                    putln!(text, "Err: bad pin\r")
+                }
+                (Some(_), None) => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad interval\r")
+                }
+            }
+            Some(text)
+        }
+        Some("deadman") => {
+            match words.next().and_then(|ms| ms.parse::<u64>().ok()) {
+                Some(ms) => {
+                    io.deadman = ms;
+                    io.last_command = io.timer.get_counter();
+                    putln!(text, "DEADMAN{}\r", ms)
+                }
+                None => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad interval\r")
+                }
+            }
+            Some(text)
+        }
+        Some("ledraw") => {
+            let state = match words.next() {
+                Some("0") => Some(Some(false)),
+                Some("1") => Some(Some(true)),
+                Some("auto") => Some(None),
+                _ => None,
+            };
+            match state {
+                Some(state) => {
+                    let now = io.timer.get_counter();
+                    io.with_leds(|leds| {
+                        let led = leds.onboard();
+                        led.manual_override = state;
+                        // Take effect now rather than at the next tick.
+                        led.run(&now);
+                    });
+                    match state {
+                        Some(on) => putln!(text, "LEDRAW{}\r", on as i32),
+                        None => putln!(text, "LEDRAW auto\r"),
+                    }
+                }
+                None => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("vsys") => {
+            putln!(text, "VSYS{}\r", io.analog.vsys());
+            Some(text)
+        }
+        Some("crc") => {
+            let start = words.next().and_then(parse_number);
+            let len = words.next().and_then(parse_number);
+            match (start, len) {
+                (Some(start), Some(len))
+                    if start
+                        .checked_add(len)
+                        .is_some_and(|end| end <= flash::FLASH_SIZE) =>
+                {
+                    putln!(text, "CRC{:08X}\r", !crc_flash(io, start, len))
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad range\r")
+                }
+            }
+            Some(text)
+        }
+        Some("dstate") => {
+            let states = io.decoder_states;
+            #[cfg(not(feature = "uart1"))]
+            putln!(text, "DSTATE usb={} uart={}\r", states[0], states[1]);
+            #[cfg(feature = "uart1")]
+            putln!(
+                text,
+                "DSTATE usb={} uart={} uart1={}\r",
+                states[0],
+                states[1],
+                states[2]
+            );
+            Some(text)
+        }
+        Some("clocks") => {
+            let frequencies = io.frequencies;
+            putln!(
+                text,
+                "CLK sys={} per={} usb={}\r",
+                frequencies.system,
+                frequencies.peripheral,
+                frequencies.usb
+            );
+            Some(text)
+        }
+        Some("mem") => {
+            putln!(
+                text,
+                "MEM stack_free={} untouched={}\r",
+                stack::free(),
+                stack::untouched()
+            );
+            Some(text)
+        }
+        Some("save") => {
+            snapshot(io).save();
+            putln!(text, "SAVED\r");
+            Some(text)
+        }
+        Some("defaults") => {
+            Config::erase();
+            putln!(text, "DEFAULTS\r");
+            Some(text)
+        }
+        Some("show") => {
+            io.pixels.show();
+            putln!(text, "SHOW\r");
+            Some(text)
+        }
+        Some("baud") => {
+            match words.next().and_then(|rate| rate.parse::<u32>().ok()) {
+                Some(rate) if io.console.set_baud(rate) => {
+                    putln!(text, "BAUD{}\r", rate)
+                }
+                _ => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad baud rate\r")
+                }
+            }
+            Some(text)
+        }
+        Some("flow") => {
+            match words.next() {
+                None => putln!(text, "FLOW{}\r", io.console.flow() as i32),
+                Some("0") => {
+                    io.console.set_flow(false);
+                    for pin in FLOW_PINS {
+                        io.gpio.release(pin);
+                    }
+                    putln!(text, "FLOW0\r")
+                }
+                Some("1") if io.console.flow() => putln!(text, "FLOW1\r"),
+                Some("1") if FLOW_PINS.iter().all(|pin| io.gpio.claim_uart(*pin)) => {
+                    io.console.set_flow(true);
+                    putln!(text, "FLOW1\r")
+                }
+                Some("1") => {
+                    io.status = Status::BadTarget;
+                    putln!(text, "Err: pins in use\r")
+                }
+                Some(_) => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad flag\r")
+                }
+            }
+            Some(text)
+        }
+        Some("uarterr") => {
+            let errors = match words.next() {
+                None | Some("0") => Some(io.console.take_errors()),
+                #[cfg(feature = "uart1")]
+                Some("1") => Some(io.console1.take_errors()),
+                _ => None,
+            };
+            match errors {
+                Some(errors) => putln!(
+                    text,
+                    "UERR oe={} fe={} pe={} brk={}\r",
+                    errors.overrun,
+                    errors.framing,
+                    errors.parity,
+                    errors.breaks
+                ),
+                None => {
+                    io.status = Status::BadTarget;
+                    putln!(text, "Err: bad uart\r")
+                }
+            }
+            Some(text)
+        }
+        Some("usbdrop") => {
+            let dropped = io.with_usb(|usb| usb.take_dropped());
+            putln!(text, "USBDROP {}\r", dropped);
+            Some(text)
+        }
+        Some("uartcfg") => {
+            // Checked before the UART is touched, so it keeps working if bad,
+            // and reported as it is without one.
+            match words.next().map(Format::parse) {
+                None => putln!(text, "UCFG {}\r", io.console.format()),
+                Some(Some(format)) => {
+                    io.console.set_format(format);
+                    putln!(text, "UCFG {}\r", format)
+                }
+                Some(None) => {
+                    io.status = Status::BadValue;
+                    putln!(text, "Err: bad format\r")
+                }
+            }
+            Some(text)
+        }
+        Some(word) => {
+            io.status = Status::UnknownCommand;
+            putln!(text, "Err: unrecognised '!{}'\r", word);
+            Some(text)
+        }
+    }
+}
+
+/// The settings `!save` keeps.
+fn snapshot<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(io: &mut Io<'_, S, D, P>) -> Config {
+    let mut leds = [(Mode::Blink, 0); LED_COUNT];
+    io.with_leds(|bank| {
+        for (target, setting) in leds.iter_mut().enumerate() {
+            if let Some(led) = bank.get(target as u8) {
+                let mode = led.steady_mode();
+                let value = match mode {
+                    Mode::Breathe => led.period,
+                    Mode::Solid => led.brightness as u64,
+                    _ => led.rate,
+                };
+                *setting = (mode, value.min(u32::MAX as u64) as u32);
+            }
+        }
+    });
+    let mut valves = 0;
+    for target in 0..VALVE_COUNT as u8 {
+        if io.valves.is_on(target) == Some(true) {
+            valves |= 1 << target;
+        }
+    }
+    Config {
+        leds,
+        valves,
+        baud: io.console.baud(),
+        actuations: io.valves.actuations(),
+        inverted: io.valves.inverted(),
+        quiet: io.quiet,
+    }
+}
+
+/// Put back settings saved by `!save`.
+fn restore<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(
+    io: &mut Io<'_, S, D, P>,
+    config: &Config,
+) {
+    io.with_leds(|leds| {
+        for (target, (mode, value)) in config.leds.iter().enumerate() {
+            if let Some(led) = leds.get_mut(target as u8) {
+                match mode {
+                    Mode::Breathe => led.set_breathe(*value as u64),
+                    Mode::Solid => led.set_brightness(*value as u8),
+                    _ => led.set_rate(*value as u64),
+                }
+            }
+        }
+    });
+    for target in 0..VALVE_COUNT as u8 {
+        io.valves
+            .set_inverted(target, config.inverted & 1 << target != 0);
+        io.valves.set(target, config.valves & 1 << target != 0);
+    }
+    // After setting the valves, which counts as actuating them.
+    io.valves.set_actuations(config.actuations);
+    io.console.set_baud(config.baud);
+    io.quiet = config.quiet;
+}
+
+/// Give queued output a moment to leave, over USB or the UART.
+fn drain<S: Shared, D: UartDevice, P: ValidUartPinout<D>>(io: &mut Io<'_, S, D, P>) {
+    io.state.console.flush(&io.state.timer, UART_WRITE_MS);
+    #[cfg(feature = "uart1")]
+    io.state.console1.flush(&io.state.timer, UART_WRITE_MS);
+    // Pending output has to reach the serial port before it can be flushed.
+    let start = io.timer.get_counter();
+    while !io.pending.is_empty() && (io.timer.get_counter() - start).to_millis() < DRAIN_MS {
+        io.flush_pending();
+        io.with_usb(|usb| usb.poll());
+    }
+    // Nothing more can be done if the host isn't reading.
+    let timer = &io.state.timer;
+    let _ = io.shared.with_usb(|usb| usb.flush(timer, DRAIN_MS));
+}
@@ -0,0 +1,127 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::{gpio::DynPin, Timer};
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// ROM and function commands used, there is only ever one device on the pin.
+const SKIP_ROM: u8 = 0xcc;
+const CONVERT_T: u8 = 0x44;
+const READ_SCRATCHPAD: u8 = 0xbe;
+
+/// Longest a 12-bit conversion takes.
+const CONVERT_MS: u64 = 750;
+
+/// Read the temperature from a DS18B20 on `pin`, in millidegrees Celsius.
+/// The pin needs an external pull-up. Returns None if nothing answers the
+/// reset or the scratchpad is corrupt.
+pub fn read_temperature(pin: &mut DynPin, timer: &Timer) -> Option<i32> {
+    reset(pin, timer)?;
+    write_byte(pin, timer, SKIP_ROM);
+    write_byte(pin, timer, CONVERT_T);
+    // The device holds the line low while it converts.
+    let start = timer.get_counter();
+    while !read_bit(pin, timer) {
+        if (timer.get_counter() - start).to_millis() > CONVERT_MS {
+            return None;
+        }
+    }
+    reset(pin, timer)?;
+    write_byte(pin, timer, SKIP_ROM);
+    write_byte(pin, timer, READ_SCRATCHPAD);
+    let mut scratchpad = [0u8; 9];
+    for byte in scratchpad.iter_mut() {
+        *byte = read_byte(pin, timer);
+    }
+    if crc8(&scratchpad[..8]) != scratchpad[8] {
+        return None;
+    }
+    // Sixteenths of a degree.
+    let raw = i16::from_le_bytes([scratchpad[0], scratchpad[1]]) as i32;
+    Some(raw * 1000 / 16)
+}
+
+// Reset pulse, Some if a device answers with its presence pulse.
+fn reset(pin: &mut DynPin, timer: &Timer) -> Option<()> {
+    let present = cortex_m::interrupt::free(|_| {
+        low(pin);
+        delay(timer, 480);
+        release(pin);
+        delay(timer, 70);
+        pin.is_low().unwrap()
+    });
+    delay(timer, 410);
+    present.then_some(())
+}
+
+// Least significant bit first.
+fn write_byte(pin: &mut DynPin, timer: &Timer, byte: u8) {
+    for bit in 0..8 {
+        write_bit(pin, timer, byte & (1 << bit) != 0);
+    }
+}
+
+fn read_byte(pin: &mut DynPin, timer: &Timer) -> u8 {
+    let mut byte = 0;
+    for bit in 0..8 {
+        if read_bit(pin, timer) {
+            byte |= 1 << bit;
+        }
+    }
+    byte
+}
+
+// Each slot is timing critical, so interrupts wait until it is over.
+fn write_bit(pin: &mut DynPin, timer: &Timer, high: bool) {
+    let (low_us, high_us) = if high { (6, 64) } else { (60, 10) };
+    cortex_m::interrupt::free(|_| {
+        low(pin);
+        delay(timer, low_us);
+        release(pin);
+    });
+    delay(timer, high_us);
+}
+
+fn read_bit(pin: &mut DynPin, timer: &Timer) -> bool {
+    let high = cortex_m::interrupt::free(|_| {
+        low(pin);
+        delay(timer, 6);
+        release(pin);
+        delay(timer, 9);
+        pin.is_high().unwrap()
+    });
+    delay(timer, 55);
+    high
+}
+
+fn low(pin: &mut DynPin) {
+    pin.into_push_pull_output();
+    pin.set_low().unwrap();
+}
+
+// Let the pull-up take the line high, or the device hold it low.
+fn release(pin: &mut DynPin) {
+    pin.into_floating_input();
+}
+
+fn delay(timer: &Timer, us: u64) {
+    let start = timer.get_counter();
+    while (timer.get_counter() - start).to_micros() < us {}
+}
+
+// Dallas/Maxim CRC-8, polynomial x^8 + x^5 + x^4 + 1.
+fn crc8(bytes: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for byte in bytes {
+        let mut byte = *byte;
+        for _ in 0..8 {
+            let mix = (crc ^ byte) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8c;
+            }
+            byte >>= 1;
+        }
+    }
+    crc
+}
@@ -0,0 +1,99 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::{pac, usb::UsbBus};
+use core::{
+    fmt::Write,
+    panic::PanicInfo,
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, Ordering},
+};
+use heapless::String;
+
+use crate::usb::Usb;
+
+/// Where the USB port was last used, for the panic handler to take over.
+static USB: AtomicPtr<Usb<'static, UsbBus>> = AtomicPtr::new(ptr::null_mut());
+/// Set while the USB port is in use, so a panic from inside leaves it alone.
+static IN_USE: AtomicBool = AtomicBool::new(false);
+
+/// The onboard led, GPIO25, already a SIO output.
+const LED: u32 = 1 << 25;
+
+/// A millisecond at the 125 MHz system clock.
+const MS_CYCLES: u32 = 125_000;
+/// Length of a Morse dot, as for the led command.
+const UNIT_MS: u32 = 150;
+
+/// Run `f` on the USB port, noting where it is for the panic handler and
+/// that it is in use meanwhile.
+pub fn using<R>(
+    usb: &mut Usb<'static, UsbBus>,
+    f: impl FnOnce(&mut Usb<'static, UsbBus>) -> R,
+) -> R {
+    USB.store(usb, Ordering::Relaxed);
+    IN_USE.store(true, Ordering::Relaxed);
+    let result = f(usb);
+    IN_USE.store(false, Ordering::Relaxed);
+    result
+}
+
+/// Report the panic over USB and blink SOS on the onboard led, forever.
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    cortex_m::interrupt::disable();
+    // Nobody is left to feed it, and a reset would hide the blinking.
+    unsafe {
+        (*pac::WATCHDOG::ptr())
+            .ctrl
+            .modify(|_, w| w.enable().clear_bit())
+    };
+    let mut message: String<128> = String::new();
+    // Whatever fits is better than nothing.
+    let _ = write!(message, "PANIC {}\r\n", info);
+    let mut sent = 0;
+    loop {
+        for letter in ["...", "---", "..."] {
+            for symbol in letter.bytes() {
+                let units = if symbol == b'-' { 3 } else { 1 };
+                led(true);
+                wait(units, &message, &mut sent);
+                led(false);
+                wait(1, &message, &mut sent);
+            }
+            // Three units between letters, one already passed.
+            wait(2, &message, &mut sent);
+        }
+        wait(4, &message, &mut sent);
+        // Repeat the message for a terminal opened since.
+        sent = 0;
+    }
+}
+
+fn led(on: bool) {
+    let sio = unsafe { &*pac::SIO::ptr() };
+    if on {
+        sio.gpio_out_set.write(|w| unsafe { w.bits(LED) });
+    } else {
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(LED) });
+    }
+}
+
+// Keep USB serviced for `units` Morse units, sending what is left of the
+// message as the host takes it.
+fn wait(units: u32, message: &str, sent: &mut usize) {
+    for _ in 0..units * UNIT_MS {
+        cortex_m::asm::delay(MS_CYCLES);
+        // Skipped if the panic came from inside a use of the port.
+        if IN_USE.load(Ordering::Relaxed) {
+            continue;
+        }
+        // Interrupts are off and nothing else runs, so nothing else has it.
+        if let Some(usb) = unsafe { USB.load(Ordering::Relaxed).as_mut() } {
+            usb.poll();
+            if let Ok(written) = usb.write(&message.as_bytes()[*sent..]) {
+                *sent += written;
+            }
+        }
+    }
+}
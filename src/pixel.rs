@@ -0,0 +1,103 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::{
+    gpio::{bank0::Gpio22, FunctionPio0, Pin, PinId},
+    pac::{PIO0, RESETS},
+    pio::{Buffers, PIOBuilder, PIOExt, PinDir, ShiftDirection, Tx, SM0},
+};
+use pio::{Assembler, JmpCondition, OutDestination, SetDestination, SideSet};
+
+/// Pixels held for the strip, any beyond these stay as they are.
+pub const PIXEL_COUNT: usize = 16;
+
+/// State machine cycles spent in each part of a bit, ten to a bit.
+const T1: u8 = 2;
+const T2: u8 = 5;
+const T3: u8 = 3;
+
+/// Divider giving 8 MHz from the 125 MHz system clock, 800 kbit/s on the wire.
+const DIV_INT: u16 = 15;
+const DIV_FRAC: u8 = 160;
+
+/// The index and colour from an `n` line, the colour is always the last six
+/// hex digits and whatever precedes it is the index. Spaces are ignored.
+pub fn parse(line: &str) -> Option<(usize, u32)> {
+    let line: heapless::Vec<u8, 64> = line.bytes().filter(|c| *c != b' ').collect();
+    // Split the bytes, a character beyond ASCII can't be cut in two.
+    if line.len() < 6 || !line.is_ascii() {
+        return None;
+    }
+    let (index, colour) = line.split_at(line.len() - 6);
+    let index = core::str::from_utf8(index).ok()?.parse().ok()?;
+    let rgb = u32::from_str_radix(core::str::from_utf8(colour).ok()?, 16).ok()?;
+    Some((index, rgb))
+}
+
+/// WS2812 addressable leds on GPIO22, driven by a PIO0 state machine.
+pub struct Pixels {
+    tx: Tx<(PIO0, SM0)>,
+    colours: [u32; PIXEL_COUNT],
+}
+
+impl Pixels {
+    /// The pin is only taken to be sure it has been handed to PIO0.
+    pub fn new(pio: PIO0, _pin: Pin<Gpio22, FunctionPio0>, resets: &mut RESETS) -> Pixels {
+        // Each bit is a high pulse, long for a one and short for a zero.
+        let mut a = Assembler::<32>::new_with_side_set(SideSet::new(false, 1, false));
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut do_zero = a.label();
+        a.set_with_side_set(SetDestination::PINDIRS, 1, 0);
+        a.bind(&mut wrap_target);
+        a.out_with_delay_and_side_set(OutDestination::X, 1, T3 - 1, 0);
+        a.jmp_with_delay_and_side_set(JmpCondition::XIsZero, &mut do_zero, T1 - 1, 1);
+        a.jmp_with_delay_and_side_set(JmpCondition::Always, &mut wrap_target, T2 - 1, 1);
+        a.bind(&mut do_zero);
+        a.nop_with_delay_and_side_set(T2 - 1, 0);
+        a.bind(&mut wrap_source);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+
+        let (mut pio, sm0, _, _, _) = pio.split(resets);
+        let installed = pio.install(&program).unwrap();
+        let pin_id = Gpio22::DYN.num;
+        let (mut sm, _, tx) = PIOBuilder::from_program(installed)
+            .buffers(Buffers::OnlyTx)
+            .side_set_pin_base(pin_id)
+            .out_shift_direction(ShiftDirection::Left)
+            .autopull(true)
+            .pull_threshold(24)
+            .clock_divisor_fixed_point(DIV_INT, DIV_FRAC)
+            .build(sm0);
+        sm.set_pindirs([(pin_id, PinDir::Output)]);
+        // The state machine runs on once started, only its FIFO is needed.
+        sm.start();
+        Pixels {
+            tx,
+            colours: [0; PIXEL_COUNT],
+        }
+    }
+
+    /// Set a pixel to `rgb` and refresh the strip. Returns false if there is
+    /// no such pixel.
+    pub fn set(&mut self, index: usize, rgb: u32) -> bool {
+        match self.colours.get_mut(index) {
+            Some(colour) => {
+                *colour = rgb & 0xff_ffff;
+                self.show();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Send every pixel down the strip, they latch once it idles low.
+    pub fn show(&mut self) {
+        for rgb in self.colours {
+            // The strip takes green first, the top 24 bits are sent.
+            let [_, r, g, b] = rgb.to_be_bytes();
+            let word = u32::from_be_bytes([g, r, b, 0]);
+            while !self.tx.write(word) {}
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::pwm::{FreeRunning, Slice, SliceId, Slices, ValidSliceMode};
+use embedded_hal::PwmPin;
+
+/// Divider and wrap giving 1 kHz from the 125 MHz system clock.
+const DUTY_DIV: u8 = 50;
+const DUTY_TOP: u16 = 2499;
+
+/// Divider and wrap giving the 50 Hz, 20 ms frame hobby servos expect.
+const SERVO_DIV: u8 = 100;
+const SERVO_TOP: u16 = 24999;
+const SERVO_FRAME_US: u32 = 20_000;
+/// Pulse widths at 0 and 180 degrees.
+const SERVO_MIN_US: u32 = 1000;
+const SERVO_MAX_US: u32 = 2000;
+pub const SERVO_MAX_ANGLE: u16 = 180;
+
+/// Slices on the RP2040, GPIO n is on slice n / 2 modulo 8.
+const SLICE_COUNT: usize = 8;
+
+/// The frequency a slice is run at, shared by both its channels.
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Period {
+    Duty,
+    Servo,
+}
+
+/// Hardware PWM, each GPIO drives channel A or B of one of the eight slices.
+pub struct Pwm {
+    slices: Slices,
+    periods: [Option<Period>; SLICE_COUNT],
+    // GPIOs set on each slice, as a mask.
+    users: [u32; SLICE_COUNT],
+}
+
+impl Pwm {
+    pub fn new(slices: Slices) -> Pwm {
+        Pwm {
+            slices,
+            periods: [None; SLICE_COUNT],
+            users: [0; SLICE_COUNT],
+        }
+    }
+
+    /// Run the pin's slice at 1 kHz with `duty` out of 255 high, for a pin
+    /// whose slice nothing else uses, as with the valves. The pin must
+    /// already be switched to its PWM function.
+    pub fn set_duty(&mut self, pin: u8, duty: u8) {
+        self.set(pin, Period::Duty, duty_level(duty as u16 * 10), |_| false);
+    }
+
+    /// As `set_duty` with the duty in tenths, clamped to 0 - 2550. Returns
+    /// false, leaving the slice alone, if another pin that `in_use` says is
+    /// still on PWM runs the slice at a different frequency.
+    pub fn set_duty_tenths(&mut self, pin: u8, tenths: u16, in_use: impl Fn(u8) -> bool) -> bool {
+        self.set(pin, Period::Duty, duty_level(tenths), in_use)
+    }
+
+    /// Run the pin's slice at 50 Hz with a pulse positioning a servo at
+    /// `angle` tenths of a degree, clamped to 0 - 180 degrees. Returns false
+    /// as `set_duty_tenths` does.
+    pub fn set_angle_tenths(&mut self, pin: u8, angle: u16, in_use: impl Fn(u8) -> bool) -> bool {
+        let full = SERVO_MAX_ANGLE as u32 * 10;
+        let angle = (angle as u32).min(full);
+        let us = SERVO_MIN_US + angle * (SERVO_MAX_US - SERVO_MIN_US) / full;
+        let level = (us * (SERVO_TOP as u32 + 1) / SERVO_FRAME_US) as u16;
+        self.set(pin, Period::Servo, level, in_use)
+    }
+
+    /// Hold every PWM output low, servos included.
+    pub fn stop(&mut self) {
+        let s = &mut self.slices;
+        silence(&mut s.pwm0);
+        silence(&mut s.pwm1);
+        silence(&mut s.pwm2);
+        silence(&mut s.pwm3);
+        silence(&mut s.pwm4);
+        silence(&mut s.pwm5);
+        silence(&mut s.pwm6);
+        silence(&mut s.pwm7);
+    }
+
+    // Both channels of a slice share its period, so it only changes once no
+    // other pin on the slice is still using it.
+    fn set(&mut self, pin: u8, period: Period, level: u16, in_use: impl Fn(u8) -> bool) -> bool {
+        let slice = ((pin >> 1) & 7) as usize;
+        let others = (0..32)
+            .filter(|other| *other != pin && self.users[slice] & 1 << other != 0)
+            .any(in_use);
+        if others && self.periods[slice] != Some(period) {
+            return false;
+        }
+        self.periods[slice] = Some(period);
+        self.users[slice] |= 1 << pin;
+        let (div, top) = match period {
+            Period::Duty => (DUTY_DIV, DUTY_TOP),
+            Period::Servo => (SERVO_DIV, SERVO_TOP),
+        };
+        let channel_b = pin & 1 == 1;
+        let s = &mut self.slices;
+        match slice {
+            0 => configure(&mut s.pwm0, channel_b, div, top, level),
+            1 => configure(&mut s.pwm1, channel_b, div, top, level),
+            2 => configure(&mut s.pwm2, channel_b, div, top, level),
+            3 => configure(&mut s.pwm3, channel_b, div, top, level),
+            4 => configure(&mut s.pwm4, channel_b, div, top, level),
+            5 => configure(&mut s.pwm5, channel_b, div, top, level),
+            6 => configure(&mut s.pwm6, channel_b, div, top, level),
+            _ => configure(&mut s.pwm7, channel_b, div, top, level),
+        }
+        true
+    }
+}
+
+// Channel level for a duty in tenths out of 2550.
+fn duty_level(tenths: u16) -> u16 {
+    let full = u8::MAX as u32 * 10;
+    (tenths.min(full as u16) as u32 * (DUTY_TOP as u32 + 1) / full) as u16
+}
+
+fn configure<S: SliceId>(
+    slice: &mut Slice<S, FreeRunning>,
+    channel_b: bool,
+    div: u8,
+    top: u16,
+    level: u16,
+) where
+    FreeRunning: ValidSliceMode<S>,
+{
+    slice.set_div_int(div);
+    slice.set_div_frac(0);
+    slice.set_top(top);
+    if channel_b {
+        slice.channel_b.set_duty(level);
+    } else {
+        slice.channel_a.set_duty(level);
+    }
+    slice.enable();
+}
+
+fn silence<S: SliceId>(slice: &mut Slice<S, FreeRunning>)
+where
+    FreeRunning: ValidSliceMode<S>,
+{
+    slice.channel_a.set_duty(0);
+    slice.channel_b.set_duty(0);
+}
@@ -0,0 +1,78 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::{
+    pac::{RESETS, SPI0},
+    spi::{Disabled, Enabled, Spi},
+};
+use embedded_hal::{blocking::spi::Transfer, spi::MODE_0};
+use fugit::{HertzU32, RateExtU32};
+
+/// SPI0 RX (MISO) on GPIO16, SCK on GPIO18 and TX (MOSI) on GPIO19. These
+/// are spare GPIOs, reserved for SPI by the first transfer.
+pub const MISO_PIN: u8 = 16;
+pub const SCK_PIN: u8 = 18;
+pub const MOSI_PIN: u8 = 19;
+/// Chip select, driven by hand so it stays low for the whole transfer.
+pub const CS_PIN: u8 = 17;
+
+/// Longest transfer, as many bytes as fit back in a response in hex.
+pub const MAX_TRANSFER: usize = (64 - "SPI\r\n".len()) / 2;
+
+const DEFAULT_BAUD: u32 = 1_000_000;
+/// Slowest and fastest clock, the fastest being half the peripheral clock.
+const MIN_BAUD: u32 = 1_000;
+const MAX_DIVISOR: u32 = 2;
+
+pub struct SpiBus {
+    spi: Spi<Enabled, SPI0, 8>,
+    frequency: HertzU32,
+    mode: u8,
+    baud: u32,
+}
+
+impl SpiBus {
+    pub fn new(spi: Spi<Disabled, SPI0, 8>, resets: &mut RESETS, frequency: HertzU32) -> SpiBus {
+        let spi = spi.init(resets, frequency, DEFAULT_BAUD.Hz(), &MODE_0);
+        SpiBus {
+            spi,
+            frequency,
+            mode: 0,
+            baud: DEFAULT_BAUD,
+        }
+    }
+
+    /// Switch to SPI mode 0 - 3 and the nearest clock at or below `baud`,
+    /// returns false leaving the bus untouched for anything else.
+    pub fn configure(&mut self, mode: u8, baud: u32) -> bool {
+        if mode > 3 || !(MIN_BAUD..=self.frequency.to_Hz() / MAX_DIVISOR).contains(&baud) {
+            return false;
+        }
+        // The HAL only sets the format as it brings the block up, and that
+        // needs the resets, so this does the same directly while disabled.
+        let registers = unsafe { &*SPI0::ptr() };
+        registers.sspcr1.modify(|_, w| w.sse().clear_bit());
+        registers
+            .sspcr0
+            .modify(|_, w| w.spo().bit(mode & 2 != 0).sph().bit(mode & 1 != 0));
+        self.baud = self.spi.set_baudrate(self.frequency, baud.Hz()).to_Hz();
+        registers.sspcr1.modify(|_, w| w.sse().set_bit());
+        self.mode = mode;
+        true
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.mode
+    }
+
+    /// The clock actually achieved, which may be below the one asked for.
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Send `bytes`, replacing each with the byte received as it went.
+    pub fn transfer(&mut self, bytes: &mut [u8]) {
+        // Transfers on this HAL never fail.
+        self.spi.transfer(bytes).unwrap();
+    }
+}
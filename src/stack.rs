@@ -0,0 +1,57 @@
+use core::ptr;
+
+/// Painted over the unused stack at boot, the deepest use overwrites it.
+const SENTINEL: u32 = 0x5354_4b21;
+
+/// Left unpainted below the stack pointer, room for `paint` itself.
+const MARGIN: usize = 256;
+
+/// Start of RAM, where flip-link moves the bottom of the stack.
+const RAM_START: usize = 0x2000_0000;
+
+extern "C" {
+    // From the cortex-m-rt linker script.
+    static _stack_start: u32;
+    static _stack_end: u32;
+}
+
+// Lowest address the stack can reach.
+fn bottom() -> usize {
+    let start = ptr::addr_of!(_stack_start) as usize;
+    let end = ptr::addr_of!(_stack_end) as usize;
+    // flip-link puts the statics above the stack, which then runs down to the
+    // start of RAM instead of into them.
+    if end < start {
+        end
+    } else {
+        RAM_START
+    }
+}
+
+fn pointer() -> usize {
+    cortex_m::register::msp::read() as usize
+}
+
+/// Fill the stack below the caller with a sentinel, call first thing at boot.
+pub fn paint() {
+    let end = pointer() - MARGIN;
+    let mut address = bottom();
+    while address < end {
+        unsafe { ptr::write_volatile(address as *mut u32, SENTINEL) };
+        address += 4;
+    }
+}
+
+/// Bytes between the stack pointer and the bottom of the stack.
+pub fn free() -> usize {
+    pointer() - bottom()
+}
+
+/// Bytes at the bottom of the stack that have never been used since boot.
+pub fn untouched() -> usize {
+    let mut address = bottom();
+    while address < pointer() && unsafe { ptr::read_volatile(address as *const u32) } == SENTINEL {
+        address += 4;
+    }
+    address - bottom()
+}
@@ -0,0 +1,63 @@
+use core::fmt::{self, Write};
+use heapless::String;
+
+/// Ends a response that didn't fit.
+const ELLIPSIS: &str = "...\r\n";
+
+/// `write!` into a `String` that can't fail, see [`write`].
+macro_rules! put {
+    ($text:expr, $($arg:tt)*) => {
+        $crate::text::write(&mut $text, format_args!($($arg)*))
+    };
+}
+pub(crate) use put;
+
+/// `writeln!` into a `String` that can't fail, see [`write`].
+macro_rules! putln {
+    ($text:expr, $($arg:tt)*) => {
+        $crate::text::write_line(&mut $text, format_args!($($arg)*))
+    };
+}
+pub(crate) use putln;
+
+// Keeps what fits, noting anything dropped.
+struct Truncating<'a, const N: usize> {
+    text: &'a mut String<N>,
+    overflow: bool,
+}
+
+impl<const N: usize> Write for Truncating<'_, N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            if self.text.push(c).is_err() {
+                self.overflow = true;
+                return Err(fmt::Error);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Append to `text`, cutting it short with an ellipsis and line ending if it
+/// fills up. Anything added once it has been cut short is dropped.
+pub fn write<const N: usize>(text: &mut String<N>, args: fmt::Arguments) {
+    if text.ends_with(ELLIPSIS) {
+        return;
+    }
+    let mut out = Truncating {
+        text,
+        overflow: false,
+    };
+    if out.write_fmt(args).is_err() || out.overflow {
+        while !text.is_empty() && text.len() + ELLIPSIS.len() > N {
+            text.pop();
+        }
+        // Too small for even the ellipsis, so what fitted will have to do.
+        let _ = text.push_str(ELLIPSIS);
+    }
+}
+
+/// As `write` followed by a newline.
+pub fn write_line<const N: usize>(text: &mut String<N>, args: fmt::Arguments) {
+    write(text, format_args!("{}\n", args));
+}
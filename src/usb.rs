@@ -1,54 +1,231 @@
-use heapless::String;
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::Timer;
+use heapless::{spsc::Queue, Vec};
 use usb_device::{class_prelude::*, prelude::*};
 use usbd_serial::SerialPort;
 
+/// Received bytes waiting for the command task, one less than this fits.
+#[cfg(not(feature = "buffer-large"))]
+const RX_QUEUE_SIZE: usize = 256;
+#[cfg(feature = "buffer-large")]
+const RX_QUEUE_SIZE: usize = 1024;
+
+/// Largest packet the serial port hands over in one read, whatever the queue
+/// size it is read a packet at a time.
+const PACKET_SIZE: usize = 64;
+
+/// USB identity, set with `USB_VID`, `USB_PID`, `USB_MANUFACTURER` and
+/// `USB_PRODUCT` when building. The default ids are unregistered test ids,
+/// a product needs its own.
+const VID: u16 = hex_id(env!("USB_VID"));
+const PID: u16 = hex_id(env!("USB_PID"));
+const MANUFACTURER: &str = env!("USB_MANUFACTURER");
+const PRODUCT: &str = env!("USB_PRODUCT");
+
+// The build script hands the ids over as four hex digits.
+const fn hex_id(hex: &str) -> u16 {
+    let bytes = hex.as_bytes();
+    let mut id = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'f' => c - b'a' + 10,
+            _ => panic!("bad USB id"),
+        };
+        id = id << 4 | digit as u16;
+        i += 1;
+    }
+    id
+}
+
+/// Longest line `read_line` holds on to.
+pub const LINE_SIZE: usize = 64;
+
+/// The serial port as the command path sees it, so a test can stand in for it.
+pub trait Serial {
+    /// Send as much as will be taken, returns how many bytes were accepted.
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, UsbError>;
+}
+
+/// Queue `bytes` behind anything already pending, then send what `serial`
+/// takes. Anything beyond what `pending` can hold is lost.
+pub fn queue<S: Serial, const N: usize>(serial: &mut S, pending: &mut Vec<u8, N>, bytes: &[u8]) {
+    let room = N - pending.len();
+    pending
+        .extend_from_slice(&bytes[..bytes.len().min(room)])
+        .unwrap();
+    flush(serial, pending);
+}
+
+/// Send `pending` from the front, keeping whatever `serial` hasn't taken yet.
+pub fn flush<S: Serial, const N: usize>(serial: &mut S, pending: &mut Vec<u8, N>) {
+    if pending.is_empty() {
+        return;
+    }
+    match serial.write(pending) {
+        Ok(written) => {
+            pending.rotate_left(written);
+            pending.truncate(pending.len() - written);
+        }
+        // No host to send to, so drop it.
+        Err(_) => pending.clear(),
+    }
+}
+
+/// Fill `line` from `next` up to its `\n` or as much as fits in `buffer`,
+/// then hand that over. Anything held beyond what `buffer` takes stays for
+/// next time, as does a partial line until the rest arrives.
+pub fn take_line<const N: usize>(
+    line: &mut Vec<u8, N>,
+    mut next: impl FnMut() -> Option<u8>,
+    buffer: &mut [u8],
+) -> Option<usize> {
+    let limit = buffer.len().min(N);
+    if limit == 0 {
+        return None;
+    }
+    while line.len() < limit {
+        let c = next()?;
+        line.push(c).unwrap();
+        if c == b'\n' {
+            break;
+        }
+    }
+    let count = line.len().min(limit);
+    buffer[..count].copy_from_slice(&line[..count]);
+    line.rotate_left(count);
+    line.truncate(line.len() - count);
+    Some(count)
+}
+
 pub struct Usb<'a, B: UsbBus> {
     device: UsbDevice<'a, B>,
     serial: SerialPort<'a, B>,
+    rx: Queue<u8, RX_QUEUE_SIZE>,
+    line: Vec<u8, LINE_SIZE>,
+    // Bytes received with the queue full, since last taken.
+    dropped: u32,
+    // Connection state as last reported by `connection_edge`.
+    connected: bool,
 }
 
 impl<'a, B: UsbBus> Usb<'a, B> {
-    pub fn new(usb_bus: &'a UsbBusAllocator<B>) -> Usb<'a, B> {
+    /// `serial_number` tells boards apart on the host, so should be unique.
+    pub fn new(usb_bus: &'a UsbBusAllocator<B>, serial_number: &'static str) -> Usb<'a, B> {
         // Set up the USB Communications Class Device driver
         let serial = SerialPort::new(usb_bus);
 
-        // Create a USB device with a fake VID and PID
-        let device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0xcafe, 0x27dd))
-            .manufacturer("Field Home I/O")
-            .product("Pico I/O Expander")
-            .serial_number("00001")
+        let device = UsbDeviceBuilder::new(usb_bus, UsbVidPid(VID, PID))
+            .manufacturer(MANUFACTURER)
+            .product(PRODUCT)
+            .serial_number(serial_number)
             .device_class(2) // from: https://www.usb.org/defined-class-codes
             .build();
 
-        Usb { device, serial }
+        Usb {
+            device,
+            serial,
+            rx: Queue::new(),
+            line: Vec::new(),
+            dropped: 0,
+            connected: false,
+        }
     }
 
-    pub fn read(&mut self, buffer: &mut [u8]) -> Option<usize> {
-        //Result<usize, UsbError> {
-        if self.device.poll(&mut [&mut self.serial]) {
-            match self.serial.read(buffer) {
-                Ok(0) => {}
-                Err(_e) => {}
-                Ok(count) => return Some(count),
-            }
+    /// Service the device and queue anything received, for the interrupt
+    /// handler. True if the serial port had anything to do, so there may be
+    /// input waiting.
+    pub fn poll(&mut self) -> bool {
+        let active = self.device.poll(&mut [&mut self.serial]);
+        if active {
+            self.receive();
         }
-        None
-    }
-
-    pub fn write(&mut self, text: &String<64>) {
-        let bytes = text.as_bytes();
-        if !bytes.is_empty() {
-            // Send response to the host
-            let mut out = &bytes[..bytes.len()];
-            while !out.is_empty() {
-                match self.serial.write(out) {
-                    Ok(len) => out = &out[len..],
-                    // On error, just drop unwritten data.
-                    // One possible error is Err(WouldBlock), meaning the USB
-                    // write buffer is full.
-                    Err(_) => break,
+        active
+    }
+
+    /// Next received byte, for the command task.
+    pub fn dequeue(&mut self) -> Option<u8> {
+        self.rx.dequeue()
+    }
+
+    /// Bytes lost to a full queue since last asked.
+    pub fn take_dropped(&mut self) -> u32 {
+        core::mem::take(&mut self.dropped)
+    }
+
+    /// True while a terminal has the port open, it asserts DTR.
+    pub fn is_connected(&self) -> bool {
+        self.serial.dtr()
+    }
+
+    /// Some(true) once each time a terminal opens the port, Some(false) once
+    /// as it closes or the cable is pulled.
+    pub fn connection_edge(&mut self) -> Option<bool> {
+        let connected = self.is_connected();
+        if connected == self.connected {
+            return None;
+        }
+        self.connected = connected;
+        Some(connected)
+    }
+
+    /// Next line including its `\n`, or as much of it as fits in `buffer`.
+    /// A partial line is held until the rest arrives.
+    pub fn read_line(&mut self, buffer: &mut [u8]) -> Option<usize> {
+        let rx = &mut self.rx;
+        take_line(&mut self.line, || rx.dequeue(), buffer)
+    }
+
+    // Move everything received into the queue, counting what doesn't fit.
+    // A packet left in the endpoint keeps the interrupt asserted, so it is
+    // always read even when it has to be dropped.
+    fn receive(&mut self) {
+        let mut buffer = [0u8; PACKET_SIZE];
+        while let Ok(count) = self.serial.read(&mut buffer) {
+            for c in &buffer[..count] {
+                if self.rx.enqueue(*c).is_err() {
+                    self.dropped = self.dropped.saturating_add(1);
                 }
             }
         }
     }
+
+    /// Send as much as the serial port will take, returns how many bytes
+    /// were accepted. Fewer than all of them means the USB buffer is full.
+    pub fn write(&mut self, bytes: &[u8]) -> Result<usize, UsbError> {
+        let mut written = 0;
+        while written < bytes.len() {
+            match self.serial.write(&bytes[written..]) {
+                Ok(len) => written += len,
+                Err(UsbError::WouldBlock) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(written)
+    }
+
+    /// Wait until everything written has gone to the host, giving up with
+    /// `WouldBlock` after `timeout_ms`. Polls the device itself, so works
+    /// with its interrupt held off.
+    pub fn flush(&mut self, timer: &Timer, timeout_ms: u64) -> Result<(), UsbError> {
+        let start = timer.get_counter();
+        loop {
+            self.poll();
+            match self.serial.flush() {
+                Ok(()) => return Ok(()),
+                Err(UsbError::WouldBlock)
+                    if (timer.get_counter() - start).to_millis() < timeout_ms => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<B: UsbBus> Serial for Usb<'_, B> {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, UsbError> {
+        Usb::write(self, bytes)
+    }
 }
@@ -0,0 +1,282 @@
+// Use alias bsp so we can switch boards at a single location
+use rp_pico as bsp;
+
+use bsp::hal::{
+    gpio::{DynPin, DYN_FUNCTION_PWM},
+    pac::SIO,
+    timer::Instant,
+};
+use core::fmt;
+use embedded_hal::digital::v2::{OutputPin, StatefulOutputPin};
+
+/// Number of valve targets addressable by the protocol (`v0` - `v9`) on this
+/// board, change it along with the pins handed to `Valves::new`.
+pub const VALVE_COUNT: usize = 10;
+
+/// How a valve's pin is driven.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Mode {
+    /// Open or closed.
+    Digital,
+    /// Proportional, a PWM duty out of 255.
+    Pwm,
+}
+
+impl Mode {
+    pub fn parse(text: &str) -> Option<Mode> {
+        match text {
+            "digital" => Some(Mode::Digital),
+            "pwm" => Some(Mode::Pwm),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Mode::Digital => write!(f, "digital"),
+            Mode::Pwm => write!(f, "pwm"),
+        }
+    }
+}
+
+/// `N` valves, at most 16 as masks hold a bit for each.
+pub struct Valves<const N: usize> {
+    pins: [Option<DynPin>; N],
+    /// When each pulsed valve is due to close.
+    closes: [Option<Instant>; N],
+    modes: [Mode; N],
+    /// Duty of each valve in PWM mode.
+    levels: [u8; N],
+    /// Times each valve has opened or closed, for maintenance.
+    actuations: [u32; N],
+    /// Bit n set for each valve n with an active-low driver, which is open
+    /// while its pin is low.
+    inverted: u16,
+}
+
+impl<const N: usize> Valves<N> {
+    const FITS_MASK: () = assert!(N <= u16::BITS as usize, "a u16 mask holds 16 valves");
+
+    pub fn new(mut pins: [Option<DynPin>; N]) -> Valves<N> {
+        let () = Self::FITS_MASK;
+        // Start with every valve closed.
+        for pin in pins.iter_mut().flatten() {
+            pin.into_push_pull_output();
+            pin.set_low().unwrap();
+        }
+        Valves {
+            pins,
+            closes: [None; N],
+            modes: [Mode::Digital; N],
+            levels: [0; N],
+            actuations: [0; N],
+            inverted: 0,
+        }
+    }
+
+    /// Choose whether a valve's driver is active-low, leaving the valve
+    /// closed. Returns false if the target has no pin assigned.
+    pub fn set_inverted(&mut self, target: u8, inverted: bool) -> bool {
+        if self.mode(target).is_none() {
+            return false;
+        }
+        let bit = 1 << target;
+        self.inverted = if inverted {
+            self.inverted | bit
+        } else {
+            self.inverted & !bit
+        };
+        match self.modes[target as usize] {
+            Mode::Digital => {
+                self.set(target, false);
+            }
+            Mode::Pwm => self.levels[target as usize] = 0,
+        }
+        true
+    }
+
+    /// Bit n set for each valve n with an active-low driver.
+    pub fn inverted(&self) -> u16 {
+        self.inverted
+    }
+
+    /// Switch how a valve is driven, leaving it closed. Returns false if the
+    /// target has no pin assigned. In PWM mode the caller drives the pin's
+    /// slice, starting from a duty of 0.
+    pub fn set_mode(&mut self, target: u8, mode: Mode) -> bool {
+        match self.pins.get_mut(target as usize) {
+            Some(Some(pin)) => {
+                let target = target as usize;
+                self.closes[target] = None;
+                self.levels[target] = 0;
+                self.modes[target] = mode;
+                match mode {
+                    Mode::Digital => {
+                        pin.into_push_pull_output();
+                        drive(pin, false, self.inverted & 1 << target != 0);
+                    }
+                    Mode::Pwm => pin.try_into_mode(DYN_FUNCTION_PWM).unwrap(),
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn mode(&self, target: u8) -> Option<Mode> {
+        match self.pins.get(target as usize) {
+            Some(Some(_)) => Some(self.modes[target as usize]),
+            _ => None,
+        }
+    }
+
+    /// Record the duty of a valve in PWM mode, returns the GPIO whose slice
+    /// should be set and the duty to drive it at, which differs for an
+    /// active-low valve. None if the valve isn't in PWM mode.
+    pub fn set_level(&mut self, target: u8, duty: u8) -> Option<(u8, u8)> {
+        match self.pins.get(target as usize) {
+            Some(Some(pin)) if self.modes[target as usize] == Mode::Pwm => {
+                let level = &mut self.levels[target as usize];
+                if (*level > 0) != (duty > 0) {
+                    self.actuations[target as usize] =
+                        self.actuations[target as usize].wrapping_add(1);
+                }
+                *level = duty;
+                let inverted = self.inverted & 1 << target != 0;
+                Some((pin.id().num, if inverted { u8::MAX - duty } else { duty }))
+            }
+            _ => None,
+        }
+    }
+
+    /// Duty of a valve in PWM mode, None otherwise.
+    pub fn level(&self, target: u8) -> Option<u8> {
+        match self.mode(target) {
+            Some(Mode::Pwm) => Some(self.levels[target as usize]),
+            _ => None,
+        }
+    }
+
+    /// Open or close a valve, returns false if the target has no pin assigned
+    /// or is in PWM mode.
+    pub fn set(&mut self, target: u8, on: bool) -> bool {
+        match self.pins.get_mut(target as usize) {
+            Some(Some(pin)) if self.modes[target as usize] == Mode::Digital => {
+                // Setting it explicitly overrides any pulse.
+                self.closes[target as usize] = None;
+                let inverted = self.inverted & 1 << target != 0;
+                if is_open(pin, inverted) != on {
+                    self.actuations[target as usize] =
+                        self.actuations[target as usize].wrapping_add(1);
+                }
+                drive(pin, on, inverted);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Open the valves whose bits are set in `mask` and close the rest, all
+    /// at the same instant. Returns false, changing nothing, if a set bit is
+    /// a target with no pin assigned. Valves in PWM mode are left to the
+    /// caller.
+    pub fn set_all(&mut self, mask: u16) -> bool {
+        if mask.checked_shr(N as u32).unwrap_or(0) != 0 {
+            return false;
+        }
+        let mut toggle = 0u32;
+        let mut changed = 0u16;
+        for (target, pin) in self.pins.iter().enumerate() {
+            let on = mask & 1 << target != 0;
+            let inverted = self.inverted & 1 << target != 0;
+            match pin {
+                Some(_) if self.modes[target] == Mode::Pwm => {}
+                Some(pin) if is_open(pin, inverted) != on => {
+                    toggle |= 1 << pin.id().num;
+                    changed |= 1 << target;
+                }
+                Some(_) => {}
+                None if on => return false,
+                None => {}
+            }
+        }
+        self.closes = [None; N];
+        for (target, count) in self.actuations.iter_mut().enumerate() {
+            if changed & 1 << target != 0 {
+                *count = count.wrapping_add(1);
+            }
+        }
+        // A single write to the xor register flips every changing valve together.
+        unsafe { (*SIO::ptr()).gpio_out_xor.write(|w| w.bits(toggle)) };
+        true
+    }
+
+    /// Open a valve until `until`, returns false if the target has no pin
+    /// assigned or is in PWM mode.
+    pub fn pulse(&mut self, target: u8, until: Instant) -> bool {
+        if !self.set(target, true) {
+            return false;
+        }
+        self.closes[target as usize] = Some(until);
+        true
+    }
+
+    /// Close any pulsed valve that is due.
+    pub fn run(&mut self, now: &Instant) {
+        let valves = self.closes.iter_mut().zip(self.pins.iter_mut());
+        for (target, ((close, pin), count)) in valves.zip(self.actuations.iter_mut()).enumerate() {
+            if let (Some(due), Some(pin)) = (*close, pin) {
+                if *now >= due {
+                    drive(pin, false, self.inverted & 1 << target != 0);
+                    *close = None;
+                    *count = count.wrapping_add(1);
+                }
+            }
+        }
+    }
+
+    /// Times each valve has opened or closed.
+    pub fn actuations(&self) -> [u32; N] {
+        self.actuations
+    }
+
+    /// Start counting from `counts`, zero or as saved.
+    pub fn set_actuations(&mut self, counts: [u32; N]) {
+        self.actuations = counts;
+    }
+
+    /// Bit n set for each valve n with a pin assigned.
+    pub fn assigned(&self) -> u16 {
+        self.pins
+            .iter()
+            .enumerate()
+            .filter(|(_, pin)| pin.is_some())
+            .fold(0, |mask, (target, _)| mask | 1 << target)
+    }
+
+    /// Whether a valve is open, at any duty for one in PWM mode.
+    pub fn is_on(&self, target: u8) -> Option<bool> {
+        match self.pins.get(target as usize) {
+            Some(Some(_)) if self.modes[target as usize] == Mode::Pwm => {
+                Some(self.levels[target as usize] > 0)
+            }
+            Some(Some(pin)) => Some(is_open(pin, self.inverted & 1 << target != 0)),
+            _ => None,
+        }
+    }
+}
+
+// Whether a digital valve is open, its pin is low when open if inverted.
+fn is_open(pin: &DynPin, inverted: bool) -> bool {
+    pin.is_set_high().unwrap() != inverted
+}
+
+fn drive(pin: &mut DynPin, open: bool, inverted: bool) {
+    if open != inverted {
+        pin.set_high().unwrap();
+    } else {
+        pin.set_low().unwrap();
+    }
+}
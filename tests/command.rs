@@ -0,0 +1,288 @@
+//! Host tests for the command path, from serial input through to the
+//! response, with a mock serial port and led pins.
+//!
+//! `cargo test --no-default-features --features std --target x86_64-unknown-linux-gnu`
+
+// The firmware sources are shared as modules, not all of them is used here.
+#[allow(dead_code, unused_macros, unused_imports)]
+#[path = "../src/text.rs"]
+mod text;
+
+#[allow(dead_code)]
+#[path = "../src/decoder.rs"]
+mod decoder;
+
+#[allow(dead_code)]
+#[path = "../src/led.rs"]
+mod led;
+
+#[allow(dead_code)]
+#[path = "../src/pixel.rs"]
+mod pixel;
+
+#[allow(dead_code)]
+#[path = "../src/usb.rs"]
+mod usb;
+
+use core::convert::Infallible;
+use decoder::{DecodeResult, Decoder, Instant, Status};
+use embedded_hal::digital::v2::{OutputPin, StatefulOutputPin};
+use heapless::Vec;
+use led::{LedBank, LedStatus, Mode};
+use std::collections::VecDeque;
+use usb::Serial;
+use usb_device::UsbError;
+
+#[derive(Default)]
+struct MockPin {
+    high: bool,
+}
+
+impl OutputPin for MockPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.high = false;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.high = true;
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for MockPin {
+    fn is_set_high(&self) -> Result<bool, Infallible> {
+        Ok(self.high)
+    }
+
+    fn is_set_low(&self) -> Result<bool, Infallible> {
+        Ok(!self.high)
+    }
+}
+
+/// Replays scripted input and records everything written, taking at most
+/// `packet` bytes per write like a USB endpoint.
+struct MockSerial {
+    input: VecDeque<u8>,
+    output: std::vec::Vec<u8>,
+    packet: usize,
+}
+
+impl MockSerial {
+    fn new(input: &[u8]) -> MockSerial {
+        MockSerial {
+            input: input.iter().copied().collect(),
+            output: std::vec::Vec::new(),
+            packet: 64,
+        }
+    }
+}
+
+impl Serial for MockSerial {
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, UsbError> {
+        let count = bytes.len().min(self.packet);
+        self.output.extend_from_slice(&bytes[..count]);
+        Ok(count)
+    }
+}
+
+// Decode everything queued and answer it through the leds' part of the
+// command path, as the firmware does.
+fn run(serial: &mut MockSerial, leds: &mut LedBank<MockPin>) -> std::vec::Vec<Status> {
+    let mut decoder: Decoder = Decoder::new();
+    let mut pending: Vec<u8, 256> = Vec::new();
+    let mut statuses = std::vec::Vec::new();
+    while let Some(c) = serial.input.pop_front() {
+        if let Some((text, status)) = leds.respond(&decoder.run(&c)) {
+            statuses.push(status);
+            usb::queue(serial, &mut pending, text.as_bytes());
+        }
+    }
+    statuses
+}
+
+fn leds() -> LedBank<MockPin> {
+    LedBank::with_pins(MockPin::default(), [Some(MockPin::default()), None, None])
+}
+
+#[test]
+fn led_rate() {
+    let mut serial = MockSerial::new(b"l250\r");
+    let mut leds = leds();
+    assert!(run(&mut serial, &mut leds) == [Status::Ok]);
+    assert_eq!(leds.get(0).unwrap().rate, 250);
+    assert_eq!(serial.output, b"LA0\r\n");
+}
+
+#[test]
+fn external_led() {
+    let mut serial = MockSerial::new(b"l1:f1000\rb1:128\r");
+    let mut leds = leds();
+    assert!(run(&mut serial, &mut leds) == [Status::Ok, Status::Ok]);
+    let led = leds.get(1).unwrap();
+    assert_eq!(led.brightness, 128);
+    assert!(led.mode == Mode::Solid);
+    assert_eq!(serial.output, b"LA1\r\nBA1\r\n");
+}
+
+#[test]
+fn missing_led() {
+    let mut serial = MockSerial::new(b"l2:100\r");
+    let mut leds = leds();
+    assert!(run(&mut serial, &mut leds) == [Status::BadTarget]);
+    assert_eq!(serial.output, b"LE2\r\n");
+}
+
+#[test]
+fn led_query() {
+    let mut serial = MockSerial::new(b"l250\rl?\rb1:?\rv1\r");
+    let mut leds = leds();
+    // The valve command is left to the rest of the command path.
+    assert!(run(&mut serial, &mut leds) == [Status::Ok, Status::Ok, Status::Ok]);
+    assert_eq!(serial.output, b"LA0\r\nLQ250\r\nBQ255\r\n");
+}
+
+#[test]
+fn bad_brightness() {
+    let mut serial = MockSerial::new(b"b300\r");
+    let mut leds = leds();
+    assert!(run(&mut serial, &mut leds) == [Status::BadValue]);
+    assert_eq!(leds.get(0).unwrap().brightness, 255);
+    assert_eq!(serial.output, b"BE0\r\n");
+}
+
+#[test]
+fn pending_output_waits() {
+    let mut serial = MockSerial::new(b"");
+    let mut pending: Vec<u8, 64> = Vec::new();
+    // Numbered responses with their codes, queued while the host isn't
+    // reading.
+    let responses: [&[u8]; 3] = [b"#7 LQ250:0\r\n", b"#8 BQ255:0\r\n", b"#0 LE2:3\r\n"];
+    serial.packet = 0;
+    for response in responses {
+        usb::queue(&mut serial, &mut pending, response);
+    }
+    assert!(serial.output.is_empty());
+    // Then taken 10 bytes a write, as the host catches up.
+    serial.packet = 10;
+    let mut flushes = 0;
+    while !pending.is_empty() {
+        usb::flush(&mut serial, &mut pending);
+        flushes += 1;
+        assert_eq!(serial.output.len(), (flushes * 10).min(34));
+    }
+    assert_eq!(flushes, 4);
+    assert_eq!(serial.output, responses.concat());
+}
+
+#[test]
+fn forwards_only_what_was_read() {
+    let mut serial = MockSerial::new(b"");
+    let mut pending: Vec<u8, 16> = Vec::new();
+    // A UART read of 3 bytes into a buffer still holding an earlier read.
+    let mut uart_buffer = [b'x'; 16];
+    uart_buffer[..3].copy_from_slice(b"abc");
+    let count = 3;
+    usb::queue(&mut serial, &mut pending, &uart_buffer[..count]);
+    assert_eq!(serial.output, b"abc");
+    assert!(pending.is_empty());
+}
+
+#[test]
+fn line_longer_than_buffer() {
+    let mut input: VecDeque<u8> = b"l250".iter().copied().collect();
+    let mut line: Vec<u8, 64> = Vec::new();
+    let mut buffer = [0u8; 8];
+    // Held until the rest arrives.
+    assert_eq!(
+        usb::take_line(&mut line, || input.pop_front(), &mut buffer),
+        None
+    );
+    // A smaller buffer takes what fits of the held line, the rest follows.
+    let mut small = [0u8; 2];
+    assert_eq!(
+        usb::take_line(&mut line, || input.pop_front(), &mut small),
+        Some(2)
+    );
+    assert_eq!(&small, b"l2");
+    input.extend(b"\r\n");
+    assert_eq!(
+        usb::take_line(&mut line, || input.pop_front(), &mut buffer),
+        Some(4)
+    );
+    assert_eq!(&buffer[..4], b"50\r\n");
+    assert!(line.is_empty());
+}
+
+#[test]
+fn manual_override() {
+    let mut leds = leds();
+    let led = leds.get_mut(0).unwrap();
+    led.set_solid(false);
+    led.manual_override = Some(true);
+    led.run(&Instant::from_ticks(0));
+    assert!(led.is_on());
+    // The mode is kept for when the state machine takes back over.
+    led.manual_override = None;
+    led.run(&Instant::from_ticks(1000));
+    assert!(!led.is_on());
+}
+
+#[test]
+fn led_status() {
+    let mut leds = leds();
+    let led = leds.get_mut(0).unwrap();
+    led.set_rate(250);
+    led.pulse(1, 50, 50);
+    // An acknowledgement pulse doesn't show as a change of mode.
+    assert!(
+        led.status()
+            == LedStatus {
+                on: false,
+                rate: 250,
+                mode: Mode::Blink,
+            }
+    );
+    assert_eq!(led.status().to_string(), "Lv0r250mBlink");
+}
+
+#[test]
+fn software_pwm_duty() {
+    let mut leds = leds();
+    let led = leds.get_mut(0).unwrap();
+    led.set_brightness(64);
+    // One 1 ms period sampled every microsecond.
+    let high = (0..1000u64)
+        .filter(|us| {
+            led.run(&Instant::from_ticks(*us));
+            led.is_on()
+        })
+        .count();
+    // 64 of 255 is just over a quarter.
+    assert!((240..=260).contains(&high), "high for {} us", high);
+}
+
+#[test]
+fn pixel_line() {
+    let line = |bytes: &[u8]| {
+        let mut decoder: Decoder = Decoder::new();
+        let mut result = DecodeResult::None;
+        for c in bytes {
+            match decoder.run(c) {
+                DecodeResult::None => {}
+                line => result = line,
+            }
+        }
+        match result {
+            DecodeResult::Line(decoder::Commands::Pixel, line) => pixel::parse(&line),
+            _ => panic!("not a pixel line"),
+        }
+    };
+    assert_eq!(line(b"n3ff8000\r"), Some((3, 0xff8000)));
+    assert_eq!(line(b"n 12 00 00 ff\r"), Some((12, 0x0000ff)));
+    assert_eq!(line(b"n\xffaaaaa\r"), None);
+    assert_eq!(line(b"n1\xffaaaaa\r"), None);
+    assert_eq!(line(b"nff\r"), None);
+}
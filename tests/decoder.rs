@@ -0,0 +1,428 @@
+//! Host tests for the command decoder, which has no hardware dependencies.
+//!
+//! `cargo test --no-default-features --features std --target x86_64-unknown-linux-gnu`
+
+// The firmware sources are shared as modules, not all of them is used here.
+#[allow(dead_code, unused_macros, unused_imports)]
+#[path = "../src/text.rs"]
+mod text;
+
+#[allow(dead_code)]
+#[path = "../src/decoder.rs"]
+mod decoder;
+
+use decoder::{BinaryDecoder, Commands, DecodeResult, Decoder, Instant, Status};
+use heapless::{String, Vec};
+
+// Feed every byte, returning the last result that wasn't None.
+fn feed(decoder: &mut Decoder, bytes: &[u8]) -> DecodeResult {
+    let mut last = DecodeResult::None;
+    for c in bytes {
+        match decoder.run(c) {
+            DecodeResult::None => {}
+            result => last = result,
+        }
+    }
+    last
+}
+
+fn decode(bytes: &[u8]) -> DecodeResult {
+    feed(&mut Decoder::new(), bytes)
+}
+
+fn command(bytes: &[u8]) -> Option<(Commands, u8, u16)> {
+    match decode(bytes) {
+        DecodeResult::Command(cmd, target, value) => Some((cmd, target, value)),
+        _ => None,
+    }
+}
+
+fn error(bytes: &[u8]) -> Option<Status> {
+    match decode(bytes) {
+        DecodeResult::Error(code, _) => Some(code),
+        _ => None,
+    }
+}
+
+// Every command completed on the way through the bytes, in order.
+fn commands(bytes: &[u8]) -> Vec<(Commands, u8, u16), 8> {
+    let mut decoder: Decoder = Decoder::new();
+    let mut found = Vec::new();
+    for c in bytes {
+        if let DecodeResult::Command(cmd, target, value) = decoder.run(c) {
+            found.push((cmd, target, value)).ok().unwrap();
+        }
+    }
+    found
+}
+
+#[test]
+fn status() {
+    assert!(command(b"s") == Some((Commands::Status, 0, 0)));
+    assert!(command(b"S") == Some((Commands::Status, 0, 0)));
+}
+
+#[test]
+fn control_codes_are_ignored() {
+    assert!(matches!(decode(b"\r\n;"), DecodeResult::None));
+}
+
+#[test]
+fn batched_commands() {
+    let found = commands(b"v1 1;v2 1;l200\r");
+    assert!(
+        found.as_slice()
+            == [
+                (Commands::Valve, 1, 1),
+                (Commands::Valve, 2, 1),
+                (Commands::Led, 0, 200),
+            ]
+    );
+}
+
+#[test]
+fn command_letters() {
+    for (letter, cmd) in [
+        (b's', Commands::Status),
+        (b'v', Commands::Valve),
+        (b'l', Commands::Led),
+        (b'b', Commands::Brightness),
+        (b'h', Commands::Help),
+        (b'?', Commands::Version),
+        (b'i', Commands::Id),
+        (b't', Commands::Temperature),
+        (b'g', Commands::Input),
+        (b'w', Commands::Output),
+        (b'p', Commands::Bias),
+        (b'a', Commands::Analog),
+        (b'm', Commands::Pwm),
+        (b'o', Commands::Servo),
+        (b'n', Commands::Pixel),
+        (b'1', Commands::OneWire),
+        (b'c', Commands::Count),
+        (b'!', Commands::System),
+    ] {
+        assert!(Commands::from_byte(letter) == Some(cmd));
+        assert!(Commands::from_byte(letter.to_ascii_uppercase()) == Some(cmd));
+    }
+}
+
+#[test]
+fn unknown_letter() {
+    for c in [b'x', b'X', b'z', b'#', 0x80, 0xff] {
+        assert!(Commands::from_byte(c).is_none());
+    }
+}
+
+#[test]
+fn all_commands_have_distinct_letters() {
+    let all = Commands::all();
+    for (n, cmd) in all.iter().enumerate() {
+        assert!(Commands::from_byte(cmd.letter()) == Some(*cmd));
+        assert!(all[n + 1..]
+            .iter()
+            .all(|other| other.letter() != cmd.letter()));
+    }
+}
+
+#[test]
+fn valve_with_target_and_value() {
+    assert!(command(b"v3 1\r") == Some((Commands::Valve, 3, 1)));
+    assert!(command(b"v12 0\r") == Some((Commands::Valve, 12, 0)));
+}
+
+#[test]
+fn valve_query() {
+    assert!(matches!(
+        decode(b"v3?\r"),
+        DecodeResult::Query(Commands::Valve, 3)
+    ));
+}
+
+#[test]
+fn led() {
+    assert!(command(b"l250\r") == Some((Commands::Led, 0, 250)));
+    assert!(command(b"l1:500\r") == Some((Commands::Led, 1, 500)));
+    assert!(matches!(
+        decode(b"l?\r"),
+        DecodeResult::Query(Commands::Led, 0)
+    ));
+}
+
+#[test]
+fn led_effects() {
+    assert!(command(b"lf1000\r") == Some((Commands::Breathe, 0, 1000)));
+    assert!(command(b"l2:m42\r") == Some((Commands::Morse, 2, 42)));
+    assert!(command(b"lon\r") == Some((Commands::Solid, 0, 1)));
+    assert!(command(b"loff\r") == Some((Commands::Solid, 0, 0)));
+}
+
+#[test]
+fn hex_value() {
+    assert!(command(b"b0xff\r") == Some((Commands::Brightness, 0, 255)));
+    assert!(command(b"l0xFF\r") == Some((Commands::Led, 0, 255)));
+    assert!(command(b"l0xFF\r") == command(b"l255\r"));
+}
+
+#[test]
+fn backspace_removes_digits() {
+    assert!(command(b"l259\x08\x080\r") == Some((Commands::Led, 0, 20)));
+    assert!(command(b"l12\x083\r") == Some((Commands::Led, 0, 13)));
+    assert!(command(b"v12\x083 50\r") == Some((Commands::Valve, 13, 50)));
+    assert!(command(b"v1\x082 1\r") == Some((Commands::Valve, 2, 1)));
+}
+
+#[test]
+fn reads_take_no_value() {
+    assert!(command(b"g4\r") == Some((Commands::Input, 4, 0)));
+    assert!(command(b"a1\r") == Some((Commands::Analog, 1, 0)));
+    assert!(command(b"c5\r") == Some((Commands::Count, 5, 0)));
+    assert!(command(b"cr5\r") == Some((Commands::CountRead, 5, 0)));
+    assert!(command(b"1w7\r") == Some((Commands::OneWire, 7, 0)));
+}
+
+#[test]
+fn two_letter_commands() {
+    assert!(command(b"?v") == Some((Commands::Version, 0, 0)));
+    assert!(command(b"id") == Some((Commands::Id, 0, 0)));
+    assert!(command(b"i\r") == Some((Commands::I2c, 0, 0)));
+    assert!(error(b"?x") == Some(Status::UnknownCommand));
+}
+
+#[test]
+fn lines() {
+    match decode(b"!baud 9600\r") {
+        DecodeResult::System(line) => assert_eq!(line.as_str(), "baud 9600"),
+        _ => panic!("expected a system line"),
+    }
+    match decode(b"iw3c0102\r") {
+        DecodeResult::Line(Commands::I2c, line) => assert_eq!(line.as_str(), "w3c0102"),
+        _ => panic!("expected an i2c line"),
+    }
+    match decode(b"n2ff0000\r") {
+        DecodeResult::Line(Commands::Pixel, line) => assert_eq!(line.as_str(), "2ff0000"),
+        _ => panic!("expected a pixel line"),
+    }
+}
+
+#[test]
+fn errors() {
+    assert!(error(b"x") == Some(Status::UnknownCommand));
+    assert!(error(b"vx") == Some(Status::BadTarget));
+    assert!(error(b"v999") == Some(Status::BadTarget));
+    assert!(error(b"l300:") == Some(Status::BadTarget));
+}
+
+#[test]
+fn unknown_command_nak() {
+    let mut decoder = Decoder::new();
+    match feed(&mut decoder, b"x") {
+        DecodeResult::Error(Status::UnknownCommand, text) => assert_eq!(text.as_str(), "?120\r\n"),
+        _ => panic!("expected a nak"),
+    }
+    decoder.verbose = true;
+    match feed(&mut decoder, b"x") {
+        DecodeResult::Error(_, text) => assert_eq!(text.as_str(), "Err: unrecognised '120'\r\n"),
+        _ => panic!("expected an error"),
+    }
+}
+
+#[test]
+fn error_returns_to_command() {
+    let mut decoder = Decoder::new();
+    assert!(matches!(feed(&mut decoder, b"x"), DecodeResult::Error(..)));
+    assert!(matches!(
+        feed(&mut decoder, b"s"),
+        DecodeResult::Command(Commands::Status, 0, 0)
+    ));
+}
+
+#[test]
+fn escape_cancels() {
+    let mut decoder = Decoder::new();
+    assert!(matches!(
+        feed(&mut decoder, b"v3 1\x1b"),
+        DecodeResult::None
+    ));
+    assert!(matches!(feed(&mut decoder, b"\r"), DecodeResult::None));
+    assert!(matches!(
+        feed(&mut decoder, b"!reset\x1b\r"),
+        DecodeResult::None
+    ));
+}
+
+#[test]
+fn value_overflow() {
+    assert!(error(b"l65536") == Some(Status::Overflow));
+    assert!(error(b"l70000") == Some(Status::Overflow));
+    assert!(command(b"l65535\r") == Some((Commands::Led, 0, 65535)));
+    assert!(error(b"b0x10000") == Some(Status::Overflow));
+}
+
+#[test]
+fn line_overflow() {
+    let mut line = [b'a'; 66];
+    line[0] = b'!';
+    assert!(error(&line) == Some(Status::Overflow));
+}
+
+#[test]
+fn larger_line() {
+    let mut line = [b'a'; 101];
+    line[0] = b'!';
+    line[100] = b'\r';
+    let mut decoder = Decoder::<128>::new();
+    let mut last = DecodeResult::None;
+    for c in line.iter() {
+        match decoder.run(c) {
+            DecodeResult::None => {}
+            result => last = result,
+        }
+    }
+    match last {
+        DecodeResult::System(text) => assert_eq!(text.len(), 99),
+        _ => panic!("expected a system line"),
+    }
+}
+
+#[test]
+fn terminator() {
+    let mut decoder = Decoder::new_with_terminator(b'\r');
+    assert!(matches!(
+        feed(&mut decoder, b"v3 1\n"),
+        DecodeResult::Error(Status::BadTerminator, _)
+    ));
+    assert!(matches!(
+        feed(&mut decoder, b"v3 1\r"),
+        DecodeResult::Command(Commands::Valve, 3, 1)
+    ));
+}
+
+#[test]
+fn timeout() {
+    let mut decoder = Decoder::new();
+    let start = Instant::from_ticks(0);
+    decoder.tick(start);
+    feed(&mut decoder, b"v3");
+    decoder.tick(start);
+    assert!(matches!(
+        decoder.tick(Instant::from_ticks(3_000_000)),
+        DecodeResult::Error(Status::Timeout, _)
+    ));
+    assert!(matches!(
+        feed(&mut decoder, b"s"),
+        DecodeResult::Command(Commands::Status, 0, 0)
+    ));
+}
+
+#[test]
+fn echo_and_recall() {
+    let mut decoder = Decoder::new();
+    decoder.echo = true;
+    assert!(matches!(decoder.run(&b'l'), DecodeResult::Echo(b'l')));
+    assert!(matches!(
+        feed(&mut decoder, b"250\r"),
+        DecodeResult::Command(Commands::Led, 0, 250)
+    ));
+    match feed(&mut decoder, b"\x1b[A") {
+        DecodeResult::Recall(bytes) => assert_eq!(bytes.as_slice(), b"l250\r"),
+        _ => panic!("expected the last command"),
+    }
+}
+
+#[test]
+fn binary_frame() {
+    let mut decoder = BinaryDecoder::new();
+    let mut last = DecodeResult::None;
+    for c in [0x02, 3, b'v', 0, 1, 3 + b'v' + 1, 0x03] {
+        last = decoder.run(&c);
+    }
+    assert!(matches!(last, DecodeResult::Command(Commands::Valve, 3, 1)));
+}
+
+#[test]
+fn binary_checksum() {
+    let mut decoder = BinaryDecoder::new();
+    let mut last = DecodeResult::None;
+    for c in [0x02, 3, b'v', 0, 1, 0, 0x03] {
+        last = decoder.run(&c);
+    }
+    assert!(matches!(last, DecodeResult::Error(Status::Checksum, _)));
+}
+
+#[test]
+fn long_text_is_truncated() {
+    let mut out: String<16> = String::new();
+    text::write(&mut out, format_args!("{}", "a very long response indeed"));
+    assert_eq!(out.as_str(), "a very long...\r\n");
+    // Nothing more is added once cut short.
+    text::write(&mut out, format_args!("b"));
+    assert_eq!(out.as_str(), "a very long...\r\n");
+}
+
+#[test]
+fn valve_mask() {
+    assert!(command(b"v*5\r") == Some((Commands::ValveAll, 0, 5)));
+    assert!(command(b"V*0x0F\r") == Some((Commands::ValveAll, 0, 15)));
+}
+
+#[test]
+fn valve_pulse() {
+    assert!(command(b"vp3 500\r") == Some((Commands::ValvePulse, 3, 500)));
+}
+
+#[test]
+fn status_json() {
+    let mut decoder = Decoder::new();
+    assert!(matches!(
+        feed(&mut decoder, b"s"),
+        DecodeResult::Command(Commands::Status, 0, 0)
+    ));
+    assert!(matches!(
+        feed(&mut decoder, b"j"),
+        DecodeResult::Command(Commands::StatusJson, 0, 0)
+    ));
+    // Anything else after the `s` starts a new command.
+    assert!(matches!(
+        feed(&mut decoder, b"sv3 1\r"),
+        DecodeResult::Command(Commands::Valve, 3, 1)
+    ));
+}
+
+#[test]
+fn sequence_number() {
+    let mut decoder = Decoder::new();
+    assert!(matches!(
+        feed(&mut decoder, b"#42v3 1\r"),
+        DecodeResult::Command(Commands::Valve, 3, 1)
+    ));
+    assert_eq!(decoder.take_sequence(), Some(42));
+    assert_eq!(decoder.take_sequence(), None);
+    assert!(error(b"#65536") == Some(Status::Overflow));
+}
+
+#[test]
+fn state_name() {
+    let mut decoder = Decoder::new();
+    assert_eq!(decoder.state_name(), "command");
+    feed(&mut decoder, b"v3");
+    assert_eq!(decoder.state_name(), "target");
+    feed(&mut decoder, b" 1");
+    assert_eq!(decoder.state_name(), "value");
+    feed(&mut decoder, b"\r!ba");
+    assert_eq!(decoder.state_name(), "line");
+}
+
+#[test]
+fn fractional_value() {
+    let mut decoder = Decoder::new();
+    assert!(matches!(
+        feed(&mut decoder, b"m2 50.5\r"),
+        DecodeResult::Command(Commands::Pwm, 2, 50)
+    ));
+    assert_eq!(decoder.take_tenths(), 5);
+    assert_eq!(decoder.take_tenths(), 0);
+    assert!(error(b"o3 90.25") == Some(Status::BadValue));
+    // Only PWM and servo values take a decimal point.
+    assert!(command(b"l25.") == Some((Commands::Led, 0, 25)));
+}